@@ -25,7 +25,7 @@ pub fn create_kafka_producer() -> anyhow::Result<FutureProducer> {
 
 pub fn make_kafka_payload<'a>(
     msg: &'a Vec<u8>,
-    topic: &'a String,
+    topic: &'a str,
     key: &'a String,
 ) -> FutureRecord<'a, String, Vec<u8>> {
     let record: FutureRecord<String, Vec<u8>> = FutureRecord::to(topic).payload(msg).key(key);
@@ -1,91 +1,170 @@
 use crate::services::congestion_control::CongestionControlState;
+use crate::services::connect::Connected;
+use crate::services::health::WorkerHealth;
 use crate::services::i_wh_req_handler::IWebhookRequestHandleService;
+use crate::services::pause::PauseFlags;
+use crate::services::queue_store::{AnyQueueStore, QueueStore};
 use crate::services::wh_req_handler::ProductServiceImpl;
 use rdkafka::producer::FutureProducer;
 use redis::aio::{ConnectionLike, ConnectionManager};
+use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct AppEnv<ProductService: IWebhookRequestHandleService + Clone = ProductServiceImpl> {
     pub request_handle_svc: ProductService,
+    // for readiness checks and pause flags, unset until redis answers
+    pub redis_conn: Connected<ConnectionManager>,
+    // the queues of the admin api
+    pub queue_store: Connected<AnyQueueStore>,
 }
 
 impl<ProductService> AppEnv<ProductService>
 where
     ProductService: IWebhookRequestHandleService + Clone,
 {
-    pub fn new(request_handle_svc: ProductService) -> Self {
-        Self { request_handle_svc }
+    pub fn new(
+        request_handle_svc: ProductService,
+        redis_conn: Connected<ConnectionManager>,
+        queue_store: Connected<AnyQueueStore>,
+    ) -> Self {
+        Self {
+            request_handle_svc,
+            redis_conn,
+            queue_store,
+        }
+    }
+}
+
+impl<ProductService> std::fmt::Debug for AppEnv<ProductService>
+where
+    ProductService: IWebhookRequestHandleService + Clone + std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppEnv")
+            .field("request_handle_svc", &self.request_handle_svc)
+            .field(
+                "redis_conn: ",
+                &self.redis_conn.get().ok().map(|conn| conn.get_db()),
+            )
+            .finish()
     }
 }
 
 // #[derive(Debug)]
-pub struct BgWorker {
-    pub(crate) redis_conn: ConnectionManager,
+pub struct BgWorker<Store: QueueStore = AnyQueueStore> {
+    // pause flags, none without redis: nothing is ever paused
+    pub(crate) redis_conn: Option<ConnectionManager>,
+    pub(crate) store: Store,
     pub(crate) cancel_token: CancellationToken,
     pub(crate) client: reqwest::Client,
-    pub(crate) cc_state: Mutex<CongestionControlState>,
+    // one per app, since each app pushes to its own downstream
+    pub(crate) cc_states: HashMap<String, Mutex<CongestionControlState>>,
+    pub(crate) health: Arc<WorkerHealth>,
 }
 
-impl BgWorker {
+impl<Store: QueueStore> BgWorker<Store> {
     pub fn new(
-        redis_conn: ConnectionManager,
+        redis_conn: Option<ConnectionManager>,
+        store: Store,
         cancel_token: CancellationToken,
         client: reqwest::Client,
     ) -> Self {
-        let cc_state = Mutex::new(CongestionControlState::default());
+        let cc_states = crate::config::get()
+            .apps
+            .iter()
+            .map(|app| {
+                (
+                    app.name.clone(),
+                    Mutex::new(CongestionControlState::default()),
+                )
+            })
+            .collect();
         Self {
             redis_conn,
+            store,
             cancel_token,
             client,
-            cc_state,
+            cc_states,
+            health: Arc::new(WorkerHealth::new()),
         }
     }
+
+    pub fn health(&self) -> Arc<WorkerHealth> {
+        self.health.clone()
+    }
+
+    pub(crate) async fn pause_flags(&self) -> redis::RedisResult<PauseFlags> {
+        load_pause_flags(self.redis_conn.as_ref()).await
+    }
 }
 
-impl std::fmt::Debug for BgWorker {
+impl<Store: QueueStore> std::fmt::Debug for BgWorker<Store> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("BgWorker")
-            .field("redis_conn: ", &self.redis_conn.get_db())
+            .field(
+                "redis_conn: ",
+                &self.redis_conn.as_ref().map(|conn| conn.get_db()),
+            )
             .finish()
     }
 }
 
-pub struct BgKafkaWorker {
+pub struct BgKafkaWorker<Store: QueueStore = AnyQueueStore> {
     kakfa_producer: FutureProducer,
-    redis_conn: ConnectionManager,
+    // pause flags, none without redis: nothing is ever paused
+    redis_conn: Option<ConnectionManager>,
+    pub(crate) store: Store,
     pub(crate) cancel_token: CancellationToken,
+    pub(crate) health: Arc<WorkerHealth>,
 }
 
-impl std::fmt::Debug for BgKafkaWorker {
+impl<Store: QueueStore> std::fmt::Debug for BgKafkaWorker<Store> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "BgKafkaWorkder {{ kafka_producer: {}, cancel_token: {:#?} }}",
-            "{...}", self.cancel_token
+            "BgKafkaWorkder {{ kafka_producer: {{...}}, cancel_token: {:#?} }}",
+            self.cancel_token
         )
     }
 }
 
-impl BgKafkaWorker {
+impl<Store: QueueStore> BgKafkaWorker<Store> {
     pub fn new(
         kakfa_producer: FutureProducer,
-        redis_conn: ConnectionManager,
+        redis_conn: Option<ConnectionManager>,
+        store: Store,
         cancel_token: CancellationToken,
     ) -> Self {
         Self {
             kakfa_producer,
             redis_conn,
+            store,
             cancel_token,
+            health: Arc::new(WorkerHealth::new()),
         }
     }
 
+    pub fn health(&self) -> Arc<WorkerHealth> {
+        self.health.clone()
+    }
+
     pub fn kafka_producer(&self) -> FutureProducer {
         self.kakfa_producer.clone()
     }
 
-    pub fn redis_conn(&self) -> ConnectionManager {
-        self.redis_conn.clone()
+    pub(crate) async fn pause_flags(&self) -> redis::RedisResult<PauseFlags> {
+        load_pause_flags(self.redis_conn.as_ref()).await
+    }
+}
+
+async fn load_pause_flags(
+    redis_conn: Option<&ConnectionManager>,
+) -> redis::RedisResult<PauseFlags> {
+    match redis_conn {
+        Some(redis_conn) => PauseFlags::load(&mut redis_conn.clone()).await,
+        None => Ok(PauseFlags::default()),
     }
 }
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, Read};
+use webhook_svc::model::admin::IngestResult;
+
+const USAGE: &str = "usage: bulk-ingest [--url URL] [--app APP] [--verify] [--expire] [--dry-run] [--batch LINES] [FILE]
+
+Pushes captured requests (NDJSON, one README-format request per line) from FILE, or stdin, through
+the receiver's POST /admin/ingest, authenticated with ADMIN_TOKEN. Prints a result per line and exits
+with 1 if any line failed.";
+
+struct Args {
+    url: String,
+    app: Option<String>,
+    verify: bool,
+    expire: bool,
+    dry_run: bool,
+    batch: usize,
+    file: Option<String>,
+}
+
+fn parse_args() -> Result<Args> {
+    let port = std::env::var("APP_PORT").unwrap_or_else(|_| "8080".to_string());
+    let mut args = Args {
+        url: format!("http://127.0.0.1:{port}"),
+        app: None,
+        verify: false,
+        expire: false,
+        dry_run: false,
+        batch: 1000,
+        file: None,
+    };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = |name: &str| argv.next().ok_or_else(|| anyhow!("{name} needs a value"));
+        match arg.as_str() {
+            "--url" => args.url = value("--url")?,
+            "--app" => args.app = Some(value("--app")?),
+            "--verify" => args.verify = true,
+            "--expire" => args.expire = true,
+            "--dry-run" => args.dry_run = true,
+            "--batch" => args.batch = value("--batch")?.parse().context("invalid --batch")?,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(anyhow!("unknown option {arg}\n\n{USAGE}")),
+            _ => args.file = Some(arg),
+        }
+    }
+    args.batch = args.batch.max(1);
+    Ok(args)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let _ = dotenvy::dotenv();
+    let args = parse_args()?;
+    let token = std::env::var("ADMIN_TOKEN").context("ADMIN_TOKEN is not set")?;
+    let input: Box<dyn Read> = match &args.file {
+        Some(path) => {
+            Box::new(std::fs::File::open(path).with_context(|| format!("failed to open {path}"))?)
+        }
+        None => Box::new(std::io::stdin()),
+    };
+
+    let client = reqwest::Client::new();
+    let mut query = vec![
+        ("verify", args.verify.to_string()),
+        ("expire", args.expire.to_string()),
+        ("dry_run", args.dry_run.to_string()),
+    ];
+    if let Some(app) = &args.app {
+        query.push(("app", app.clone()));
+    }
+    let mut total = IngestResult {
+        dry_run: args.dry_run,
+        ..Default::default()
+    };
+    let mut lines = std::io::BufReader::new(input).lines();
+    // lines sent before the current batch, results are numbered within their batch
+    let mut offset = 0;
+    loop {
+        let mut batch = String::new();
+        let mut count = 0;
+        for line in lines.by_ref().take(args.batch) {
+            batch.push_str(&line?);
+            batch.push('\n');
+            count += 1;
+        }
+        if count == 0 {
+            break;
+        }
+        let result: IngestResult = client
+            .post(format!("{}/admin/ingest", args.url.trim_end_matches('/')))
+            .bearer_auth(&token)
+            .query(&query)
+            .header(http::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(batch)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| {
+                format!(
+                    "failed to ingest lines {} to {}",
+                    offset + 1,
+                    offset + count
+                )
+            })?
+            .json()
+            .await?;
+        for mut line in result.lines {
+            line.line += offset;
+            println!("{}", serde_json::to_string(&line)?);
+        }
+        total.accepted += result.accepted;
+        total.existing += result.existing;
+        total.dropped += result.dropped;
+        total.failed += result.failed;
+        offset += count;
+    }
+    eprintln!(
+        "{} accepted, {} already queued, {} dropped, {} failed{}",
+        total.accepted,
+        total.existing,
+        total.dropped,
+        total.failed,
+        if total.dry_run { " (dry run)" } else { "" }
+    );
+    if total.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
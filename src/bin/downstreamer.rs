@@ -14,7 +14,11 @@ async fn main() -> Result<()> {
         .with(EnvFilter::from_default_env())
         .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE))
         .init();
-    let _lockfile = Lockfile::create(&cnf.bg_worker_lockfile)?;
+    // stream workers share the queues through their consumer group instead
+    let _lockfile = match cnf.queue_backend {
+        config::QueueBackend::Zset => Some(Lockfile::create(&cnf.bg_worker_lockfile)?),
+        config::QueueBackend::Stream => None,
+    };
 
     let token = tokio_util::sync::CancellationToken::new();
     let worker_token = token.clone();
@@ -26,14 +30,24 @@ async fn main() -> Result<()> {
         .await
         .context("failed to connect to redis")?;
 
+    let client = reqwest::ClientBuilder::new()
+        .tcp_keepalive(std::time::Duration::from_secs(60))
+        .build()?;
     let worker = app::BgWorker::new(
-        redis_conn,
+        Some(redis_conn.clone()),
+        services::queue_store::AnyQueueStore::from_config(redis_conn.clone(), None),
         worker_token,
-        reqwest::ClientBuilder::new()
-            .tcp_keepalive(std::time::Duration::from_secs(60))
-            .build()?,
+        client.clone(),
     );
 
+    let health_state = http::router::health::WorkerHealthState {
+        heartbeat: worker.health(),
+        redis_conn,
+        target: http::router::health::WorkerTarget::Downstream(client),
+    };
+    http::router::health::serve_worker(&cnf.downstreamer_health_addr, health_state, token.clone())
+        .await?;
+
     tracing::info!("starting background jobs");
     let worker_handler = worker.start_bg()?;
     let abort_worker = worker_handler.abort_handle();
@@ -1,10 +1,21 @@
 use anyhow::{Context, Result};
 use once_cell::sync::Lazy;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{
     fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
 use webhook_svc::app::AppEnv;
+use webhook_svc::http::listener::Listener;
+use webhook_svc::http::tls::TlsReloader;
+use webhook_svc::services::connect::{connect, Connected};
+use webhook_svc::services::ingest_rules::IngestRules;
+use webhook_svc::services::payload_schema::PayloadSchemas;
+use webhook_svc::services::queue_store::AnyQueueStore;
+use webhook_svc::services::spool::Spool;
+use webhook_svc::services::write_coalescer::WriteCoalescer;
 use webhook_svc::{http::router, *};
 
 #[tokio::main(flavor = "multi_thread")]
@@ -18,25 +29,81 @@ async fn main() -> Result<()> {
     let redis_cli =
         redis::Client::open(cnf.redis_url.as_str()).context("failed to init redis client")?;
 
-    let redis_conn = redis::aio::ConnectionManager::new(redis_cli)
-        .await
-        .context("failed to connect to redis")?;
+    // opened before redis is connected, so that webhooks of a receiver started during an outage
+    // are kept
+    let spool = if cnf.spool_dir.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Spool::open(&cnf.spool_dir, cnf.spool_max_bytes)?))
+    };
+
+    let load_shedder = Arc::new(services::load_shed::LoadShedder::new());
+
+    let ingest_rules = if cnf.ingest_rules_path.is_empty() {
+        Arc::new(IngestRules::default())
+    } else {
+        Arc::new(IngestRules::load(&cnf.ingest_rules_path)?)
+    };
+    tokio::spawn(ingest_rules.clone().watch(cnf.ingest_rules_reload_interval));
 
-    let product_svc = services::wh_req_handler::ProductServiceImpl::new(redis_conn);
-    let app = AppEnv::new(product_svc);
+    // set once redis answers, the receiver serves regardless
+    let redis_conn = Connected::pending();
+    let store = Connected::pending();
+    let rate_limit_conn = Connected::pending();
+    tokio::spawn({
+        let (redis_conn, store, rate_limit_conn) =
+            (redis_conn.clone(), store.clone(), rate_limit_conn.clone());
+        let load_shedder = load_shedder.clone();
+        let spool = spool.clone();
+        async move {
+            let conn = connect(redis_cli).await;
+            tracing::info!("connected to redis");
+            tokio::spawn({
+                let conn = conn.clone();
+                async move { load_shedder.watch(conn).await }
+            });
+            let write_coalescer = (cnf.write_batch_size > 0).then(|| {
+                WriteCoalescer::start(
+                    conn.clone(),
+                    cnf.write_batch_size as usize,
+                    Duration::from_micros(cnf.write_batch_linger_us),
+                    cnf.write_batch_atomic,
+                )
+            });
+            let queue_store = AnyQueueStore::from_config(conn.clone(), write_coalescer);
+            if let Some(spool) = spool {
+                tokio::spawn(spool.watch(queue_store.clone(), cnf.spool_replay_interval));
+            }
+            if cnf.rate_limit_per_sec > 0.0 {
+                rate_limit_conn.set(conn.clone());
+            }
+            store.set(queue_store);
+            redis_conn.set(conn);
+        }
+    });
+
+    let product_svc = services::wh_req_handler::ProductServiceImpl::new(
+        rate_limit_conn,
+        store.clone(),
+        load_shedder,
+        ingest_rules,
+        Arc::new(PayloadSchemas::load(&cnf.payload_schemas)?),
+        spool,
+    );
+    let app = AppEnv::new(product_svc, redis_conn, store);
     let router = router::new(app).await;
 
     tracing::info!("starting axum server");
-    let socket_addr = format!("{}:{}", cnf.app_host, cnf.app_port);
-    // let listener = tokio::net::TcpListener::bind(&socket_addr).await?;
-    let sock = tokio::net::TcpSocket::new_v4()?;
-    // sock.set_recv_buffer_size(200000)?;
-    sock.set_reuseport(true)?;
-    sock.bind(socket_addr.parse()?)?;
-    let listener = sock.listen(10000)?;
+    let listeners = cnf
+        .listeners
+        .iter()
+        .map(Listener::bind)
+        .collect::<Result<Vec<_>>>()?;
 
-    axum::serve(listener, router)
-        .with_graceful_shutdown(async move {
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
             let ctrl_c = async {
                 signal::ctrl_c()
                     .await
@@ -58,9 +125,22 @@ async fn main() -> Result<()> {
                 tracing::info!("SIGTERM received, graceful shutdown...")
                 },
             };
-        })
+            shutdown.cancel();
+        }
+    });
+
+    let tls = if cnf.tls_cert_path.is_empty() || cnf.tls_key_path.is_empty() {
+        None
+    } else {
+        // every process behind the SO_REUSEPORT socket loads and reloads the certificate itself
+        let tls = Arc::new(TlsReloader::load(&cnf.tls_cert_path, &cnf.tls_key_path)?);
+        tokio::spawn(tls.clone().watch(cnf.tls_reload_interval, shutdown.clone()));
+        tracing::info!("serving https");
+        Some(tls)
+    };
+    http::server::serve(listeners, router, tls, shutdown)
         .await
-        .context("axum server failed")?;
+        .context("server failed")?;
     tracing::info!("process terminated");
     Ok(())
 }
@@ -4,3 +4,29 @@ pub const XSHOPIFY_SHOP_DOMAIN: &str = "x-shopify-shop-domain";
 pub const XSHOPIFY_APIVERSION: &str = "x-shopify-api-version";
 pub const XSHOPIFY_WEBHOOK_ID: &str = "x-shopify-webhook-id";
 pub const XSHOPIFY_TRIGGERED_AT: &str = "x-shopify-triggered-at";
+
+pub const XHUB_SIGNATURE_256: &str = "x-hub-signature-256";
+pub const XGITHUB_EVENT: &str = "x-github-event";
+pub const XGITHUB_DELIVERY: &str = "x-github-delivery";
+pub const XGITHUB_HOOK_TARGET_ID: &str = "x-github-hook-installation-target-id";
+
+pub const STRIPE_SIGNATURE: &str = "stripe-signature";
+
+pub const XSLACK_SIGNATURE: &str = "x-slack-signature";
+pub const XSLACK_REQUEST_TIMESTAMP: &str = "x-slack-request-timestamp";
+
+// redis keys outside of the {shop}:{topic} queues
+pub const WEBHOOK_ID_KEY_PREFIX: &str = "webhook-id";
+pub const DUPLICATE_WEBHOOKS_KEY: &str = "stats:duplicate-webhooks";
+pub const RATE_LIMIT_KEY_PREFIX: &str = "rate-limit";
+// quarantine:{shop}:{topic} ZSETs of events that failed schema validation, never delivered
+pub const QUARANTINE_KEY_PREFIX: &str = "quarantine";
+// stream-owner:{queue} leases of the stream backend, naming the consumer reading the queue
+pub const STREAM_OWNER_KEY_PREFIX: &str = "stream-owner";
+// stream-entry:{queue}:{sha256 of the envelope} of the stream backend, an envelope XADDed without a
+// webhook id to deduplicate by. Streams would take it again otherwise, unlike ZSETs
+pub const STREAM_ENTRY_KEY_PREFIX: &str = "stream-entry";
+// set on events enqueued despite failing schema validation
+pub const XWEBHOOK_SCHEMA_ERRORS: &str = "x-webhook-schema-errors";
+// hash of pause flags shared by every app, fields are the paused scopes
+pub const PAUSE_FLAGS_KEY: &str = "pause-flags";
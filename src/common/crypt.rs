@@ -1,7 +1,63 @@
+use chrono::Utc;
 use data_encoding::BASE64;
 use ring::hmac;
-pub fn hmac_256_verify(key_bytes: &[u8], payload: &[u8], digest: &str) -> anyhow::Result<()> {
-    let key = hmac::Key::new(hmac::HMAC_SHA256, key_bytes);
+
+use crate::config::ClientSecret;
+
+// tries every currently active secret, returns the one the signature was made with
+pub fn hmac_256_verify<'a>(
+    secrets: &'a [ClientSecret],
+    payload: &[u8],
+    digest: &str,
+) -> anyhow::Result<&'a ClientSecret> {
     let digest_decoded = BASE64.decode(digest.as_bytes())?;
-    hmac::verify(&key, payload, digest_decoded.as_slice()).map_err(Into::into)
+    hmac_256_find(secrets, &[payload], &digest_decoded)
+        .ok_or_else(|| anyhow::anyhow!("signature doesn't match any active secret"))
+}
+
+// same as hmac_256_verify but over a message made of several parts (e.g. timestamp + body, to
+// avoid copying the body) and an already decoded digest
+pub fn hmac_256_find<'a>(
+    secrets: &'a [ClientSecret],
+    message: &[&[u8]],
+    digest: &[u8],
+) -> Option<&'a ClientSecret> {
+    let now = Utc::now();
+    secrets.iter().filter(|s| s.is_active(now)).find(|s| {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, s.secret.as_bytes());
+        let mut ctx = hmac::Context::with_key(&key);
+        message.iter().for_each(|part| ctx.update(part));
+        ring::constant_time::verify_slices_are_equal(ctx.sign().as_ref(), digest).is_ok()
+    })
+}
+
+#[test]
+fn test_hmac_256_verify_rotation() {
+    let secret = |id: &str, not_after: Option<chrono::DateTime<Utc>>| ClientSecret {
+        id: id.to_string(),
+        secret: format!("{id}-secret"),
+        not_before: None,
+        not_after,
+    };
+    let payload = br#"{"id":9079211262258}"#;
+    let sign = |secret: &str| {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        BASE64.encode(hmac::sign(&key, payload).as_ref())
+    };
+    let secrets = vec![secret("new", None), secret("old", None)];
+    let matched = hmac_256_verify(&secrets, payload, &sign("old-secret")).unwrap();
+    assert_eq!(matched.id, "old");
+    let matched = hmac_256_verify(&secrets, payload, &sign("new-secret")).unwrap();
+    assert_eq!(matched.id, "new");
+
+    // expired secrets are no longer accepted
+    let expired = vec![
+        secret("new", None),
+        secret(
+            "old",
+            Some(Utc::now() - chrono::TimeDelta::try_hours(1).unwrap()),
+        ),
+    ];
+    assert!(hmac_256_verify(&expired, payload, &sign("old-secret")).is_err());
+    assert!(hmac_256_verify(&expired, payload, "not base64!").is_err());
 }
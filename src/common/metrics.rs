@@ -0,0 +1,446 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use prometheus::{
+    register_gauge, register_gauge_vec, register_histogram, register_histogram_vec,
+    register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, Gauge, GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+
+// label values must stay bounded: app names come from the config, topics are only taken from
+// verified requests, and shop domains are opt-in (METRICS_SHOP_LABEL)
+pub const UNKNOWN: &str = "unknown";
+
+pub static WEBHOOKS_ACCEPTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_accepted_total",
+        "Webhooks enqueued for delivery",
+        &["app", "topic"]
+    )
+    .unwrap()
+});
+
+pub static WEBHOOKS_ACCEPTED_BY_SHOP: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_accepted_by_shop_total",
+        "Webhooks enqueued for delivery, per shop",
+        &["app", "shop", "topic"]
+    )
+    .unwrap()
+});
+
+pub static WEBHOOKS_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_rejected_total",
+        "Webhooks rejected at ingestion",
+        &["app", "reason", "topic"]
+    )
+    .unwrap()
+});
+
+pub static WEBHOOKS_DUPLICATE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_duplicate_total",
+        "Webhooks acknowledged but not enqueued since their id was already seen",
+        &["app", "topic"]
+    )
+    .unwrap()
+});
+
+pub static WEBHOOKS_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_dropped_total",
+        "Webhooks acknowledged but not enqueued because of an ingest rule",
+        &["app", "topic"]
+    )
+    .unwrap()
+});
+
+pub static TOPIC_MISMATCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_topic_mismatch_total",
+        "Webhooks whose route doesn't match their topic header",
+        &["app", "topic"]
+    )
+    .unwrap()
+});
+
+pub static SCHEMA_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_schema_failures_total",
+        "Schema validation failures of webhook payloads, by the failing keyword's schema path",
+        &["app", "topic", "schema_path"]
+    )
+    .unwrap()
+});
+
+pub static HMAC_VERIFY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "webhook_hmac_verify_seconds",
+        "Time spent verifying webhook signatures",
+        &["app"],
+        vec![0.000_005, 0.00001, 0.000_025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.005]
+    )
+    .unwrap()
+});
+
+pub static ENVELOPE_ENCODE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "webhook_envelope_encode_seconds",
+        "Time spent serializing (bitcode) and compressing (zstd) requests",
+        &["app"],
+        vec![0.00001, 0.000_025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.01]
+    )
+    .unwrap()
+});
+
+pub static ENVELOPE_BYTES: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "webhook_envelope_bytes",
+        "Size of compressed envelopes stored in the queues",
+        &["app"],
+        prometheus::exponential_buckets(256.0, 2.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+// disk spool, only with SPOOL_DIR set
+pub static SPOOLED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_spooled_total",
+        "Webhooks written to the disk spool because redis was unreachable",
+        &["app", "topic"]
+    )
+    .unwrap()
+});
+
+pub static SPOOL_RECORDS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "webhook_spool_records",
+        "Webhooks in the disk spool waiting to be replayed into redis"
+    )
+    .unwrap()
+});
+
+pub static SPOOL_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "webhook_spool_bytes",
+        "Size of the disk spool's records waiting to be replayed"
+    )
+    .unwrap()
+});
+
+pub static SPOOL_REPLAYED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "webhook_spool_replayed_total",
+        "Spooled webhooks replayed into redis"
+    )
+    .unwrap()
+});
+
+pub static SPOOL_CORRUPT: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "webhook_spool_corrupt_total",
+        "Corrupt spool records found on replay, set aside with everything after them"
+    )
+    .unwrap()
+});
+
+pub static SPOOL_REFUSED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "webhook_spool_refused_total",
+        "Spooled webhooks redis refused on replay, set aside"
+    )
+    .unwrap()
+});
+
+pub static STREAM_RECLAIMED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "webhook_stream_reclaimed_total",
+        "Pending stream entries taken over from other consumers (QUEUE_BACKEND=stream)"
+    )
+    .unwrap()
+});
+
+// write coalescing, only with WRITE_BATCH_SIZE set
+pub static WRITE_BATCH_SIZE: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "webhook_write_batch_size",
+        "Webhooks written to redis per coalesced batch",
+        vec![1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0]
+    )
+    .unwrap()
+});
+
+pub static WRITE_BATCH_WAIT_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "webhook_write_batch_wait_seconds",
+        "Time the first write of a coalesced batch waited for the rest",
+        vec![0.000_05, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01]
+    )
+    .unwrap()
+});
+
+pub static WRITE_BATCH_MAX_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "webhook_write_batch_max_size",
+        "Configured upper bound of coalesced batches (WRITE_BATCH_SIZE)"
+    )
+    .unwrap()
+});
+
+pub static WRITE_BATCH_LINGER_SECONDS: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "webhook_write_batch_linger_seconds",
+        "Configured time writes are gathered for (WRITE_BATCH_LINGER_US)"
+    )
+    .unwrap()
+});
+
+pub static REDIS_ENQUEUE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "webhook_redis_enqueue_seconds",
+        "Latency of the redis ZADD (or deduplicating script) enqueueing a request",
+        &["app"],
+        vec![0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5]
+    )
+    .unwrap()
+});
+
+pub static LOAD_LEVEL: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "webhook_load_level",
+        "Load shedding level of the receiver: 0 normal, 1 soft limit, 2 hard limit"
+    )
+    .unwrap()
+});
+
+pub static BACKLOG: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "webhook_backlog",
+        "Requests waiting across every queue, as last sampled for load shedding"
+    )
+    .unwrap()
+});
+
+pub static REDIS_USED_MEMORY_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "webhook_redis_used_memory_bytes",
+        "Redis' used_memory, as last sampled for load shedding"
+    )
+    .unwrap()
+});
+
+// downstreamer and kafka_producer
+
+pub static QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "webhook_queue_depth",
+        "Number of requests waiting in the queues of a topic, across shops",
+        &["app", "topic"]
+    )
+    .unwrap()
+});
+
+pub static QUEUE_OLDEST_AGE_SECONDS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "webhook_queue_oldest_age_seconds",
+        "Age of the oldest request waiting in the queues of a topic, from when its event was triggered",
+        &["app", "topic"]
+    )
+    .unwrap()
+});
+
+pub static DELIVERY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "webhook_delivery_seconds",
+        "Latency of pushing a request to the downstream",
+        &["app"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap()
+});
+
+pub static DELIVERY_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_delivery_retries_total",
+        "Downstream pushes retried, by what went wrong (4xx, 5xx or timeout)",
+        &["app", "status_class"]
+    )
+    .unwrap()
+});
+
+pub static DROPPED_TOO_OLD: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "webhook_dropped_too_old_total",
+        "Requests dropped from the queues for being too old to deliver",
+        &["app"]
+    )
+    .unwrap()
+});
+
+pub static CC_RTT_MIN_SECONDS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "webhook_cc_rtt_min_seconds",
+        "Lowest downstream round trip seen by the congestion control",
+        &["app"]
+    )
+    .unwrap()
+});
+
+pub static CC_RTT_MAX_SECONDS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "webhook_cc_rtt_max_seconds",
+        "Highest downstream round trip seen by the congestion control",
+        &["app"]
+    )
+    .unwrap()
+});
+
+pub static CC_SLEEP_SECONDS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "webhook_cc_sleep_seconds",
+        "Current delay between downstream pushes chosen by the congestion control",
+        &["app"]
+    )
+    .unwrap()
+});
+
+pub static KAFKA_SEND_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "webhook_kafka_send_seconds",
+        "Latency of producing a request to kafka",
+        &["app"],
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0]
+    )
+    .unwrap()
+});
+
+pub static KAFKA_COMMIT_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "webhook_kafka_commit_seconds",
+        "Latency of committing kafka transactions",
+        &["app"],
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 10.0]
+    )
+    .unwrap()
+});
+
+// last sampled (depth, oldest score) of each non-empty queue, by (app, topic). A shop's queue
+// is never a label value, the gauges add its topic's queues up
+type QueueSamples = HashMap<(String, String), HashMap<String, (i64, Option<f64>)>>;
+static QUEUE_SAMPLES: Lazy<Mutex<QueueSamples>> = Lazy::new(Default::default);
+
+// the depth of a queue and the age of its oldest request, from its score. A topic's gauges are
+// the sum of its queues' depths and the age of the oldest request among them, and are removed
+// once all its queues are drained
+pub fn record_queue_stats(
+    app: &str,
+    topic: &str,
+    queue: &str,
+    depth: i64,
+    oldest_score: Option<f64>,
+) {
+    let mut samples = QUEUE_SAMPLES.lock().unwrap();
+    let key = (app.to_string(), topic.to_string());
+    let queues = samples.entry(key.clone()).or_default();
+    if depth > 0 {
+        queues.insert(queue.to_string(), (depth, oldest_score));
+    } else {
+        queues.remove(queue);
+    }
+    if queues.is_empty() {
+        samples.remove(&key);
+    }
+    export_topic(&samples, app, topic);
+}
+
+// forgets the queues that are gone without having been sampled empty, e.g. purged
+pub fn retain_queue_stats(queues: &[String]) {
+    let mut samples = QUEUE_SAMPLES.lock().unwrap();
+    let mut emptied = vec![];
+    samples.retain(|key, sampled| {
+        sampled.retain(|queue, _| queues.contains(queue));
+        if sampled.is_empty() {
+            emptied.push(key.clone());
+        }
+        !sampled.is_empty()
+    });
+    for (app, topic) in emptied {
+        export_topic(&samples, &app, &topic);
+    }
+}
+
+fn export_topic(samples: &QueueSamples, app: &str, topic: &str) {
+    let Some(queues) = samples.get(&(app.to_string(), topic.to_string())) else {
+        // the series of a drained topic would otherwise stay at their last value
+        let _ = QUEUE_DEPTH.remove_label_values(&[app, topic]);
+        let _ = QUEUE_OLDEST_AGE_SECONDS.remove_label_values(&[app, topic]);
+        return;
+    };
+    let depth = queues.values().map(|(depth, _)| depth).sum();
+    let age = queues
+        .values()
+        .filter_map(|(_, score)| *score)
+        .reduce(f64::min)
+        .map_or(0.0, |score| {
+            (chrono::Utc::now().timestamp_millis() as f64 - score).max(0.0) / 1000.0
+        });
+    QUEUE_DEPTH.with_label_values(&[app, topic]).set(depth);
+    QUEUE_OLDEST_AGE_SECONDS
+        .with_label_values(&[app, topic])
+        .set(age);
+}
+
+// everything registered so far, in prometheus' text format
+pub fn encode() -> anyhow::Result<String> {
+    let mut buf = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[test]
+fn test_encode() {
+    WEBHOOKS_REJECTED
+        .with_label_values(&["omega", "bad_signature", UNKNOWN])
+        .inc();
+    let text = encode().unwrap();
+    assert!(text.contains(
+        r#"webhook_rejected_total{app="omega",reason="bad_signature",topic="unknown"} "#
+    ));
+}
+
+#[test]
+fn test_record_queue_stats() {
+    let now = chrono::Utc::now().timestamp_millis() as f64;
+    record_queue_stats(
+        "sigma",
+        "orders/create",
+        "sigma:a:orders/create",
+        2,
+        Some(now),
+    );
+    record_queue_stats(
+        "sigma",
+        "orders/create",
+        "sigma:b:orders/create",
+        3,
+        Some(now - 60000.0),
+    );
+    let depth = || {
+        QUEUE_DEPTH
+            .with_label_values(&["sigma", "orders/create"])
+            .get()
+    };
+    assert_eq!(depth(), 5);
+    let age = QUEUE_OLDEST_AGE_SECONDS
+        .with_label_values(&["sigma", "orders/create"])
+        .get();
+    assert!((60.0..70.0).contains(&age));
+    record_queue_stats("sigma", "orders/create", "sigma:a:orders/create", 0, None);
+    assert_eq!(depth(), 3);
+    retain_queue_stats(&[]);
+    assert!(!encode().unwrap().contains(r#"app="sigma""#));
+}
@@ -1,3 +1,4 @@
 // pub mod compde;
 pub mod consts;
 pub mod crypt;
+pub mod metrics;
@@ -0,0 +1,156 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::Provider;
+use crate::common::consts;
+
+// rewrites a request's headers: `keep` (when set, every other header goes) then `drop` filter
+// them, `rename` moves values to another name and `add` sets static values. Names are matched
+// case-insensitively, exactly or by prefix when they end with `*` (e.g. "x-forwarded-*")
+#[derive(Deserialize, Debug, Clone)]
+pub struct HeaderPolicy {
+    #[serde(default)]
+    pub keep: Vec<String>,
+    #[serde(default)]
+    pub drop: Vec<String>,
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+    #[serde(default)]
+    pub add: HashMap<String, String>,
+    // the provider's signature headers are left alone by keep, drop and rename, so the downstream
+    // can verify the request again
+    #[serde(default = "default_preserve_signature")]
+    pub preserve_signature: bool,
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        Self {
+            keep: vec![],
+            drop: vec![],
+            rename: HashMap::new(),
+            add: HashMap::new(),
+            preserve_signature: default_preserve_signature(),
+        }
+    }
+}
+
+fn default_preserve_signature() -> bool {
+    true
+}
+
+fn signature_headers(provider: Provider) -> &'static [&'static str] {
+    match provider {
+        Provider::Shopify => &[consts::XSHOPIFY_HMAC_SHA256],
+        Provider::Github => &[consts::XHUB_SIGNATURE_256],
+        Provider::Stripe => &[consts::STRIPE_SIGNATURE],
+        Provider::Slack => &[consts::XSLACK_SIGNATURE, consts::XSLACK_REQUEST_TIMESTAMP],
+    }
+}
+
+fn name_matches(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        }
+    })
+}
+
+impl HeaderPolicy {
+    pub fn is_noop(&self) -> bool {
+        self.keep.is_empty()
+            && self.drop.is_empty()
+            && self.rename.is_empty()
+            && self.add.is_empty()
+    }
+
+    pub fn apply(&self, provider: Provider, headers: &mut HeaderMap) {
+        if self.is_noop() {
+            return;
+        }
+        let protected = |name: &HeaderName| {
+            self.preserve_signature && signature_headers(provider).contains(&name.as_str())
+        };
+        let mut rewritten = HeaderMap::with_capacity(headers.len());
+        let mut current = None;
+        // values of a repeated header come with `None` names after the first
+        for (name, value) in std::mem::take(headers) {
+            let name = match name {
+                Some(name) => {
+                    current = Some(name.clone());
+                    name
+                }
+                None => match &current {
+                    Some(name) => name.clone(),
+                    None => continue,
+                },
+            };
+            if protected(&name) {
+                rewritten.append(name, value);
+                continue;
+            }
+            if (!self.keep.is_empty() && !name_matches(&self.keep, name.as_str()))
+                || name_matches(&self.drop, name.as_str())
+            {
+                continue;
+            }
+            let renamed = self
+                .rename
+                .iter()
+                .find(|(from, _)| from.eq_ignore_ascii_case(name.as_str()))
+                .and_then(|(_, to)| HeaderName::try_from(to.as_str()).ok());
+            rewritten.append(renamed.unwrap_or(name), value);
+        }
+        for (name, value) in &self.add {
+            match (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                (Ok(name), Ok(value)) if !protected(&name) => {
+                    rewritten.insert(name, value);
+                }
+                _ => tracing::warn!("header {name:?} of the header policy not added"),
+            }
+        }
+        *headers = rewritten;
+    }
+}
+
+#[test]
+fn test_header_policy() {
+    let policy: HeaderPolicy = serde_json::from_str(
+        r#"{
+            "drop": ["host", "content-length", "x-forwarded-*", "X-Shopify-Hmac-Sha256"],
+            "rename": {"X-Shopify-Topic": "x-event-topic"},
+            "add": {"x-source": "webhook-svc"}
+        }"#,
+    )
+    .unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("host", HeaderValue::from_static("hooks.example.com"));
+    headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4"));
+    headers.insert("x-shopify-hmac-sha256", HeaderValue::from_static("sig"));
+    headers.insert("x-shopify-topic", HeaderValue::from_static("orders/create"));
+    headers.append("accept", HeaderValue::from_static("a"));
+    headers.append("accept", HeaderValue::from_static("b"));
+    policy.apply(Provider::Shopify, &mut headers);
+    assert!(headers.get("host").is_none());
+    assert!(headers.get("x-forwarded-for").is_none());
+    assert_eq!(headers["x-shopify-hmac-sha256"], "sig");
+    assert!(headers.get("x-shopify-topic").is_none());
+    assert_eq!(headers["x-event-topic"], "orders/create");
+    assert_eq!(headers.get_all("accept").iter().count(), 2);
+    assert_eq!(headers["x-source"], "webhook-svc");
+
+    let keep: HeaderPolicy =
+        serde_json::from_str(r#"{"keep": ["x-shopify-topic"], "preserve_signature": false}"#)
+            .unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-shopify-hmac-sha256", HeaderValue::from_static("sig"));
+    headers.insert("x-shopify-topic", HeaderValue::from_static("orders/create"));
+    keep.apply(Provider::Shopify, &mut headers);
+    assert_eq!(headers.len(), 1);
+}
@@ -1,8 +1,220 @@
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
 use serde_inline_default::serde_inline_default;
 
+use crate::common::consts;
+
+mod header_policy;
+
+pub use header_policy::HeaderPolicy;
+
+// one of the app's client secrets, a webhook is accepted if its signature matches any secret
+// that is active at the time it's received
+#[derive(Deserialize, Clone)]
+pub struct ClientSecret {
+    pub id: String,
+    pub secret: String,
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+impl std::fmt::Debug for ClientSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientSecret")
+            .field("id", &self.id)
+            .field("not_before", &self.not_before)
+            .field("not_after", &self.not_after)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientSecret {
+    pub fn is_active(&self, at: DateTime<Utc>) -> bool {
+        !self.not_before.is_some_and(|nbf| at < nbf) && !self.not_after.is_some_and(|naf| naf <= at)
+    }
+}
+
+pub const DEFAULT_APP: &str = "default";
+
+// who sends an app's webhooks, decides how they're verified and queued
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    Shopify,
+    Github,
+    Stripe,
+    Slack,
+}
+
+// one app served by this deployment: webhooks to /webhook/{name}[/...] are verified with its
+// secrets, queued under its prefix and delivered to its own downstream/kafka topic
+#[derive(Deserialize, Debug, Clone)]
+pub struct AppConfig {
+    pub name: String,
+    #[serde(default)]
+    pub provider: Provider,
+    #[serde(default)]
+    pub client_secrets: Vec<ClientSecret>,
+    // how far (in seconds) a signed timestamp may be from now, for providers that sign one
+    #[serde(default = "default_signature_tolerance")]
+    pub signature_tolerance: u64,
+    // defaults to the app's name, only the default app has no prefix
+    #[serde(default)]
+    pub queue_prefix: String,
+    // default to DOWNSTREAM_APP_URL and KAFKA_TOPIC
+    #[serde(default)]
+    pub downstream_app_url: String,
+    #[serde(default)]
+    pub kafka_topic: String,
+}
+
+impl AppConfig {
+    // prefixes any redis key owned by the app
+    pub fn redis_key(&self, key: &str) -> String {
+        if self.queue_prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}:{}", self.queue_prefix, key)
+        }
+    }
+
+    pub fn queue_key(&self, shop: &str, topic: &str) -> String {
+        self.redis_key(&format!("{shop}:{topic}"))
+    }
+
+    pub fn quarantine_key(&self, shop: &str, topic: &str) -> String {
+        self.redis_key(&format!("{}:{shop}:{topic}", consts::QUARANTINE_KEY_PREFIX))
+    }
+
+    // (shop, topic) of one of the app's queues
+    pub fn parse_queue_key<'a>(&self, queue: &'a str) -> Option<(&'a str, &'a str)> {
+        let key = if self.queue_prefix.is_empty() {
+            queue
+        } else {
+            queue
+                .strip_prefix(self.queue_prefix.as_str())?
+                .strip_prefix(':')?
+        };
+        key.split_once(':')
+    }
+
+    pub fn is_quarantine(&self, queue: &str) -> bool {
+        self.parse_queue_key(queue)
+            .is_some_and(|(shop, _)| shop == consts::QUARANTINE_KEY_PREFIX)
+    }
+}
+
+// what happens to a webhook whose payload doesn't match its topic's schema
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaFailure {
+    #[default]
+    Reject,
+    // enqueued in a quarantine queue the workers don't deliver from
+    Quarantine,
+    // enqueued as usual, with the failures in a header
+    Flag,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PayloadSchema {
+    pub topic: String,
+    // json schema file
+    pub path: std::path::PathBuf,
+    #[serde(default)]
+    pub on_failure: SchemaFailure,
+}
+
+// how the {resource}/{topic} of a shopify webhook's route is compared with its x-shopify-topic
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicPathCheck {
+    // mismatches are rejected
+    Strict,
+    // mismatches are logged and counted
+    #[default]
+    Warn,
+    // the route's topic is ignored, even when the header is missing
+    Off,
+}
+
+// how the {shop}:{topic} queues are kept in redis
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+    // sorted sets by triggered-at, drained by a single worker (BG_WORKER_LOCKFILE)
+    #[default]
+    Zset,
+    // streams read through a consumer group by any number of workers, one per queue at a time
+    Stream,
+}
+
+fn default_signature_tolerance() -> u64 {
+    300
+}
+
+// a socket the receiver accepts connections on, every listener serves the same router
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ListenerConfig {
+    Tcp4 {
+        addr: std::net::SocketAddrV4,
+        #[serde(default = "default_backlog")]
+        backlog: u32,
+        #[serde(default = "default_true")]
+        reuseport: bool,
+        // only applies once TLS_CERT_PATH and TLS_KEY_PATH are set
+        #[serde(default = "default_true")]
+        tls: bool,
+    },
+    // dual-stack unless only_v6
+    Tcp6 {
+        addr: std::net::SocketAddrV6,
+        #[serde(default = "default_backlog")]
+        backlog: u32,
+        #[serde(default = "default_true")]
+        reuseport: bool,
+        #[serde(default)]
+        only_v6: bool,
+        #[serde(default = "default_true")]
+        tls: bool,
+    },
+    // always plain http. A stale socket file is replaced, `mode` is octal (e.g. "660")
+    Unix {
+        path: std::path::PathBuf,
+        #[serde(default = "default_backlog")]
+        backlog: u32,
+        #[serde(default)]
+        mode: Option<String>,
+    },
+}
+
+fn default_backlog() -> u32 {
+    10000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// shopify's mandatory compliance webhooks, and uninstalls
+fn default_must_accept_topics() -> Vec<String> {
+    [
+        "customers/data_request",
+        "customers/redact",
+        "shop/redact",
+        "app/uninstalled",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
 #[serde_inline_default]
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -10,6 +222,37 @@ pub struct Config {
     pub app_host: String,
     #[serde_inline_default(8080)]
     pub app_port: u32,
+    // json list of listeners, e.g.
+    // [{"type":"tcp6","addr":"[::]:8080"},{"type":"unix","path":"/run/webhook.sock","mode":"660"}]
+    // defaults to a single tcp listener on APP_HOST:APP_PORT
+    #[serde(default, deserialize_with = "from_json_str")]
+    pub listeners: Vec<ListenerConfig>,
+    // pem files, the receiver serves https when both are set. Reloaded on SIGHUP, and when they
+    // change (checked every TLS_RELOAD_INTERVAL seconds, 0 to only reload on SIGHUP)
+    #[serde(default)]
+    pub tls_cert_path: String,
+    #[serde(default)]
+    pub tls_key_path: String,
+    #[serde_inline_default(10)]
+    pub tls_reload_interval: u64,
+    // json accept/drop/reject rules by topic, shop and api version, the receiver accepts
+    // everything when unset. Reloaded like the tls certificate
+    #[serde(default)]
+    pub ingest_rules_path: String,
+    #[serde_inline_default(10)]
+    pub ingest_rules_reload_interval: u64,
+    // json header policies, applied to requests before they're enqueued and again before they're
+    // delivered (downstream or kafka)
+    #[serde(default, deserialize_with = "from_json_str")]
+    pub ingest_header_policy: HeaderPolicy,
+    #[serde(default, deserialize_with = "from_json_str")]
+    pub delivery_header_policy: HeaderPolicy,
+    // json list of the schemas payloads are checked against before being enqueued, by topic
+    #[serde(default, deserialize_with = "from_json_str")]
+    pub payload_schemas: Vec<PayloadSchema>,
+    // strict, warn or off. Unless off, the route's topic stands in for a missing x-shopify-topic
+    #[serde(default)]
+    pub topic_path_check: TopicPathCheck,
     #[serde_inline_default("error".to_string())]
     pub rust_log: String,
     pub redis_url: String,
@@ -19,17 +262,106 @@ pub struct Config {
     pub kafka_topic: String,
     #[serde_inline_default("bg_kafka_wrk".to_string())]
     pub kafka_tx_id: String,
-    pub shopify_client_secret: String,
+    // single secret, kept for existing deployments. It's tried after SHOPIFY_CLIENT_SECRETS under
+    // the key id "default"
+    pub shopify_client_secret: Option<String>,
+    // JSON list of ClientSecret, newest first
+    #[serde(default, deserialize_with = "from_json_str")]
+    pub shopify_client_secrets: Vec<ClientSecret>,
     #[serde_inline_default("http://localhost:8888".to_string())]
     pub downstream_app_url: String,
     #[serde_inline_default("/tmp/background_worker.pid".to_string())]
     pub bg_worker_lockfile: String,
+    // zset or stream, every process must agree. With streams the lockfile isn't used: the workers
+    // share STREAM_GROUP, a worker (STREAM_CONSUMER, the hostname by default) reads a queue while
+    // it holds the queue's lease of STREAM_LEASE_MS, renewed while it delivers the queue
+    #[serde(default)]
+    pub queue_backend: QueueBackend,
+    #[serde_inline_default("webhook-workers".to_string())]
+    pub stream_group: String,
+    #[serde(default)]
+    pub stream_consumer: String,
+    #[serde_inline_default(300000)]
+    pub stream_lease_ms: u64,
     #[serde_inline_default(0)]
     pub base_delay_ms: u64,
     #[serde_inline_default(5)]
     pub worker_rest: u64,
     #[serde_inline_default(500)]
     pub worker_batch_size: u64,
+    // how long (in seconds) a seen x-shopify-webhook-id is remembered, 0 disables deduplication
+    #[serde_inline_default(259200)]
+    pub webhook_dedup_ttl: u64,
+    // the receiver gathers up to WRITE_BATCH_SIZE enqueues, for at most WRITE_BATCH_LINGER_US
+    // microseconds, into one pipelined redis round trip (MULTI/EXEC with WRITE_BATCH_ATOMIC).
+    // 0 writes each webhook on its own
+    #[serde_inline_default(0)]
+    pub write_batch_size: u64,
+    #[serde_inline_default(1000)]
+    pub write_batch_linger_us: u64,
+    #[serde_inline_default(false)]
+    pub write_batch_atomic: bool,
+    // local directory the receiver spools webhooks to when redis is unreachable, replayed every
+    // SPOOL_REPLAY_INTERVAL seconds once it's back. Unset, such webhooks get a 503
+    #[serde(default)]
+    pub spool_dir: String,
+    #[serde_inline_default(1073741824)]
+    pub spool_max_bytes: u64,
+    #[serde_inline_default(5)]
+    pub spool_replay_interval: u64,
+    // per shop token bucket of the receiver: refill rate in webhooks per second (0 disables it) and
+    // bucket size. With RATE_LIMIT_PER_TOPIC each topic of a shop gets its own bucket
+    #[serde_inline_default(0.0)]
+    pub rate_limit_per_sec: f64,
+    #[serde_inline_default(100)]
+    pub rate_limit_burst: u64,
+    #[serde_inline_default(false)]
+    pub rate_limit_per_topic: bool,
+    // load shedding of the receiver: above a soft limit of queued requests (across all queues) or of
+    // redis' used_memory (in bytes), low priority topics get 503; above a hard limit, everything
+    // but the must-accept topics does. 0 disables a limit
+    #[serde_inline_default(0)]
+    pub shed_soft_backlog: u64,
+    #[serde_inline_default(0)]
+    pub shed_hard_backlog: u64,
+    #[serde_inline_default(0)]
+    pub shed_soft_memory: u64,
+    #[serde_inline_default(0)]
+    pub shed_hard_memory: u64,
+    // comma separated topics
+    #[serde(default)]
+    pub shed_low_priority_topics: Vec<String>,
+    #[serde_inline_default(default_must_accept_topics())]
+    pub shed_must_accept_topics: Vec<String>,
+    #[serde_inline_default(5)]
+    pub shed_check_interval: u64,
+    // Retry-After (in seconds) of shed webhooks
+    #[serde_inline_default(60)]
+    pub shed_retry_after: u64,
+    // dependency checks of the health endpoints, in milliseconds
+    #[serde_inline_default(1000)]
+    pub health_timeout_ms: u64,
+    #[serde_inline_default(200)]
+    pub health_slow_ms: u64,
+    // health listeners of downstreamer and kafka_producer, empty to disable
+    #[serde_inline_default("127.0.0.1:8081".to_string())]
+    pub downstreamer_health_addr: String,
+    #[serde_inline_default("127.0.0.1:8082".to_string())]
+    pub kafka_producer_health_addr: String,
+    // a worker whose loop made no progress for this long (in seconds) is reported down
+    #[serde_inline_default(300)]
+    pub worker_heartbeat_timeout: u64,
+    // bearer token of the admin api, which is disabled when empty
+    #[serde(default)]
+    pub admin_token: String,
+    // label accepted webhook counters with the shop domain too, one series per shop so keep it off
+    // for apps installed on many shops
+    #[serde_inline_default(false)]
+    pub metrics_shop_label: bool,
+    // JSON list of AppConfig. The settings above make up the "default" app, served at
+    // /webhook/{resource}/{topic} with unprefixed queues
+    #[serde(default, deserialize_with = "from_json_str")]
+    pub apps: Vec<AppConfig>,
     // #[serde_inline_default("localhost".to_string())]
     // pub db_host: String,
     // #[serde_inline_default(3306)]
@@ -43,20 +375,164 @@ pub struct Config {
 static CONFIG: Lazy<Config> = Lazy::new(|| load_config().unwrap());
 // pub fn load_config() -> std::result::Result<Config, Box<dyn std::error::Error>> {
 fn load_config() -> Result<Config> {
-    dotenvy::dotenv()?;
-    envy::from_env::<Config>()
+    // the environment alone is enough, e.g. in containers
+    match dotenvy::dotenv() {
+        Ok(_) => {}
+        Err(e) if e.not_found() => {}
+        Err(e) => return Err(e.into()),
+    }
+    let mut cnf = envy::from_env::<Config>()
         .map_err(|e| anyhow!(e))
         .context(format!(
             "at {} line {} column {}",
             file!(),
             line!(),
             column!(),
-        ))
+        ))?;
+    if let Some(secret) = cnf.shopify_client_secret.take() {
+        cnf.shopify_client_secrets.push(ClientSecret {
+            id: "default".to_string(),
+            secret,
+            not_before: None,
+            not_after: None,
+        });
+    }
+    if !cnf.shopify_client_secrets.is_empty() {
+        if cnf.apps.iter().any(|app| app.name == DEFAULT_APP) {
+            return Err(anyhow!("app name {DEFAULT_APP} is reserved"));
+        }
+        cnf.apps.insert(
+            0,
+            AppConfig {
+                name: DEFAULT_APP.to_string(),
+                provider: Provider::Shopify,
+                client_secrets: cnf.shopify_client_secrets.clone(),
+                signature_tolerance: default_signature_tolerance(),
+                queue_prefix: String::new(),
+                downstream_app_url: String::new(),
+                kafka_topic: String::new(),
+            },
+        );
+    }
+    if cnf.listeners.is_empty() {
+        let addr: std::net::SocketAddr = format!("{}:{}", cnf.app_host, cnf.app_port)
+            .parse()
+            .context("invalid APP_HOST or APP_PORT")?;
+        cnf.listeners.push(match addr {
+            std::net::SocketAddr::V4(addr) => ListenerConfig::Tcp4 {
+                addr,
+                backlog: default_backlog(),
+                reuseport: true,
+                tls: true,
+            },
+            std::net::SocketAddr::V6(addr) => ListenerConfig::Tcp6 {
+                addr,
+                backlog: default_backlog(),
+                reuseport: true,
+                only_v6: false,
+                tls: true,
+            },
+        });
+    }
+    // an empty bucket never refills, a negative rate makes waits negative
+    if cnf.rate_limit_per_sec.is_nan() || cnf.rate_limit_per_sec < 0.0 {
+        return Err(anyhow!("RATE_LIMIT_PER_SEC must be 0 or more"));
+    }
+    if cnf.rate_limit_burst < 1 {
+        return Err(anyhow!("RATE_LIMIT_BURST must be at least 1"));
+    }
+    if cnf.apps.is_empty() {
+        return Err(anyhow!(
+            "either SHOPIFY_CLIENT_SECRET, SHOPIFY_CLIENT_SECRETS or APPS must be set"
+        ));
+    }
+    for i in 0..cnf.apps.len() {
+        let app = &cnf.apps[i];
+        if app.client_secrets.is_empty() {
+            return Err(anyhow!("app {} has no client secret", app.name));
+        }
+        if cnf.apps[..i].iter().any(|other| other.name == app.name) {
+            return Err(anyhow!("app {} is defined more than once", app.name));
+        }
+        let app = &mut cnf.apps[i];
+        if app.queue_prefix.is_empty() && app.name != DEFAULT_APP {
+            app.queue_prefix = app.name.clone();
+        }
+        if app.downstream_app_url.is_empty() {
+            app.downstream_app_url = cnf.downstream_app_url.clone();
+        }
+        if app.kafka_topic.is_empty() {
+            app.kafka_topic = cnf.kafka_topic.clone();
+        }
+    }
+    Ok(cnf)
     // envy::from_env::<Config>().map_err(Into::into)
     // envy::from_env::<Config>().map_err(|e| Box::new(e) as Box<dyn Error>)
     // envy::from_env::<Config>().map_err(|e| Box::<dyn std::error::Error>::from(e))
 }
 
+// for structured values passed as a JSON string in a single env variable
+fn from_json_str<'de, D: Deserializer<'de>, T: DeserializeOwned>(de: D) -> Result<T, D::Error> {
+    let raw = String::deserialize(de)?;
+    serde_json::from_str(&raw).map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    pub fn app(&self, name: &str) -> Option<&AppConfig> {
+        self.apps.iter().find(|app| app.name == name)
+    }
+
+    // the app a queue belongs to, going by its prefix. Unprefixed queues belong to the default
+    // app, if there is one
+    pub fn app_for_queue(&self, queue: &str) -> Option<&AppConfig> {
+        self.apps
+            .iter()
+            .filter(|app| !app.queue_prefix.is_empty())
+            .find(|app| {
+                queue
+                    .strip_prefix(app.queue_prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with(':'))
+            })
+            .or_else(|| self.app(DEFAULT_APP))
+    }
+
+    pub fn is_quarantine_queue(&self, queue: &str) -> bool {
+        self.app_for_queue(queue)
+            .is_some_and(|app| app.is_quarantine(queue))
+    }
+
+    // a restarted worker keeps the name, and with it the leases and pending entries it had
+    pub fn consumer_name(&self) -> String {
+        if !self.stream_consumer.is_empty() {
+            return self.stream_consumer.clone();
+        }
+        std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| "worker".to_string())
+    }
+}
+
+// what tests need to load the config without a .env: a shopify app (secret "test") and a redis
+// url nothing connects to unless the test does
+#[cfg(test)]
+pub fn init_test_env() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        for (key, value) in [
+            ("REDIS_URL", "redis://127.0.0.1/"),
+            ("SHOPIFY_CLIENT_SECRET", "test"),
+        ] {
+            if std::env::var_os(key).is_none() {
+                // only ever set once, before the config is loaded
+                unsafe { std::env::set_var(key, value) };
+            }
+        }
+    });
+}
+
 pub fn get() -> &'static Lazy<Config> {
     // let cnf = unsafe { CONFIG.get_or_insert(load_config().unwrap()) };
     // cnf
@@ -0,0 +1,114 @@
+use anyhow::Context;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::config::ListenerConfig;
+
+pub enum Listener {
+    // whether it serves https, when the receiver has a certificate
+    Tcp { listener: TcpListener, tls: bool },
+    Unix(UnixListener),
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    // must be called from within the tokio runtime
+    pub fn bind(cnf: &ListenerConfig) -> anyhow::Result<Self> {
+        let listener = match cnf {
+            ListenerConfig::Tcp4 {
+                addr,
+                backlog,
+                reuseport,
+                tls,
+            } => Listener::Tcp {
+                listener: bind_tcp(Domain::IPV4, (*addr).into(), *backlog, *reuseport, None)?,
+                tls: *tls,
+            },
+            ListenerConfig::Tcp6 {
+                addr,
+                backlog,
+                reuseport,
+                only_v6,
+                tls,
+            } => Listener::Tcp {
+                listener: bind_tcp(
+                    Domain::IPV6,
+                    (*addr).into(),
+                    *backlog,
+                    *reuseport,
+                    Some(*only_v6),
+                )?,
+                tls: *tls,
+            },
+            ListenerConfig::Unix {
+                path,
+                backlog,
+                mode,
+            } => Listener::Unix(bind_unix(path, *backlog, mode.as_deref())?),
+        };
+        tracing::info!("listening on {cnf:?}");
+        Ok(listener)
+    }
+
+    // (stream, peer address for logs)
+    pub async fn accept(&self) -> std::io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp { listener, .. } => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), peer.to_string()))
+            }
+            Listener::Unix(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Stream::Unix(stream), format!("{peer:?}")))
+            }
+        }
+    }
+}
+
+fn bind_tcp(
+    domain: Domain,
+    addr: std::net::SocketAddr,
+    backlog: u32,
+    reuseport: bool,
+    only_v6: Option<bool>,
+) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(domain, Type::STREAM, None)?;
+    if let Some(only_v6) = only_v6 {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(reuseport)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("failed to bind {addr}"))?;
+    socket.listen(backlog as i32)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+fn bind_unix(path: &Path, backlog: u32, mode: Option<&str>) -> anyhow::Result<UnixListener> {
+    // left behind by a previous run that didn't shut down cleanly. Anything else at that path is
+    // an error
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path).with_context(|| format!("failed to remove stale {path:?}"))?;
+    }
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SockAddr::unix(path)?)
+        .with_context(|| format!("failed to bind {path:?}"))?;
+    if let Some(mode) = mode {
+        let mode = u32::from_str_radix(mode, 8)
+            .with_context(|| format!("invalid mode {mode:?} for {path:?}"))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set the mode of {path:?}"))?;
+    }
+    socket.listen(backlog as i32)?;
+    Ok(UnixListener::from_std(socket.into())?)
+}
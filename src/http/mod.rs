@@ -1 +1,4 @@
+pub mod listener;
 pub mod router;
+pub mod server;
+pub mod tls;
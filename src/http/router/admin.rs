@@ -0,0 +1,230 @@
+use crate::app::AppEnv;
+use crate::config::DEFAULT_APP;
+use crate::model::admin::{
+    EventPage, EventQuery, ExportQuery, ImportQuery, ImportResult, IngestQuery, IngestResult,
+    MoveRequest, MoveResult, PauseInfo, PauseRequest, PauseScope, PurgeRequest, PurgeResult,
+    QueueFilter, QueueInfo,
+};
+use crate::model::error::AdminError;
+use crate::services::pause::{self, PauseFlags};
+use crate::services::queue_admin::QueueAdmin;
+use axum::body::Body;
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{DefaultBodyLimit, Query, Request, State};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use http::HeaderMap;
+use std::sync::Arc;
+
+// NDJSON imports are far bigger than webhooks
+const IMPORT_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+// mounted under /admin, only when ADMIN_TOKEN is set
+pub fn router() -> Router<Arc<AppEnv>> {
+    Router::new()
+        .route("/queues", get(list_queues))
+        .route("/events", get(list_events))
+        .route("/queues/purge", post(purge_queue))
+        .route("/queues/move", post(move_queue))
+        .route("/queues/export", get(export_queue))
+        .route(
+            "/queues/import",
+            post(import_queue).layer(DefaultBodyLimit::max(IMPORT_MAX_BYTES)),
+        )
+        .route(
+            "/ingest",
+            post(ingest_requests).layer(DefaultBodyLimit::max(IMPORT_MAX_BYTES)),
+        )
+        .route(
+            "/pauses",
+            get(list_pauses).put(pause_scope).delete(resume_scope),
+        )
+        .route_layer(middleware::from_fn(require_token))
+}
+
+async fn require_token(
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, AdminError> {
+    let token = &crate::config::get().admin_token;
+    let given = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(AdminError::Unauthorized)?;
+    if token.is_empty()
+        || ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_err()
+    {
+        return Err(AdminError::Unauthorized);
+    }
+    Ok(next.run(request).await)
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn list_queues(
+    State(app): State<Arc<AppEnv>>,
+    filter: Result<Query<QueueFilter>, QueryRejection>,
+) -> Result<Json<Vec<QueueInfo>>, AdminError> {
+    let Query(filter) = filter.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let queues = QueueAdmin::new(app.queue_store.get()?.clone())
+        .list_queues(&filter)
+        .await?;
+    Ok(Json(queues))
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn list_events(
+    State(app): State<Arc<AppEnv>>,
+    query: Result<Query<EventQuery>, QueryRejection>,
+) -> Result<Json<EventPage>, AdminError> {
+    let Query(query) = query.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let page = QueueAdmin::new(app.queue_store.get()?.clone())
+        .page(&query)
+        .await?;
+    Ok(Json(page))
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn purge_queue(
+    State(app): State<Arc<AppEnv>>,
+    request: Result<Json<PurgeRequest>, JsonRejection>,
+) -> Result<Json<PurgeResult>, AdminError> {
+    let Json(request) = request.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let result = QueueAdmin::new(app.queue_store.get()?.clone())
+        .purge(&request)
+        .await?;
+    Ok(Json(result))
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn move_queue(
+    State(app): State<Arc<AppEnv>>,
+    request: Result<Json<MoveRequest>, JsonRejection>,
+) -> Result<Json<MoveResult>, AdminError> {
+    let Json(request) = request.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    if request.from == request.to {
+        return Err(AdminError::BadRequest(
+            "source and destination are the same queue".to_string(),
+        ));
+    }
+    // the workers never read quarantine queues, other sources must be out of their way
+    let cnf = crate::config::get();
+    if !request.dry_run
+        && !cnf.is_quarantine_queue(&request.from)
+        && !PauseFlags::load(&mut app.redis_conn.get()?.clone())
+            .await?
+            .is_queue_paused(&request.from)
+    {
+        return Err(AdminError::Conflict(format!(
+            "{} must be paused before its events are moved",
+            request.from
+        )));
+    }
+    let result = QueueAdmin::new(app.queue_store.get()?.clone())
+        .move_queue(&request)
+        .await?;
+    Ok(Json(result))
+}
+
+// streamed in chunks, a failure midway cuts the response short
+#[tracing::instrument(level = "debug")]
+pub async fn export_queue(
+    State(app): State<Arc<AppEnv>>,
+    query: Result<Query<ExportQuery>, QueryRejection>,
+) -> Result<Response, AdminError> {
+    let Query(query) = query.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let admin = QueueAdmin::new(app.queue_store.get()?.clone());
+    let chunks = futures::stream::try_unfold(Some(0), move |offset| {
+        let admin = admin.clone();
+        let query = query.clone();
+        async move {
+            let Some(offset) = offset else {
+                return Ok(None);
+            };
+            let (lines, next) = admin.export_chunk(&query, offset).await?;
+            Ok::<_, redis::RedisError>(Some((lines, next)))
+        }
+    });
+    Ok((
+        [(http::header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+#[tracing::instrument(level = "debug", skip(ndjson))]
+pub async fn import_queue(
+    State(app): State<Arc<AppEnv>>,
+    query: Result<Query<ImportQuery>, QueryRejection>,
+    ndjson: String,
+) -> Result<Json<ImportResult>, AdminError> {
+    let Query(query) = query.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let result = QueueAdmin::new(app.queue_store.get()?.clone())
+        .import(&query, &ndjson)
+        .await?;
+    Ok(Json(result))
+}
+
+// body: captured requests as NDJSON, one README-format request per line
+#[tracing::instrument(level = "debug", skip(ndjson))]
+pub async fn ingest_requests(
+    State(app): State<Arc<AppEnv>>,
+    query: Result<Query<IngestQuery>, QueryRejection>,
+    ndjson: String,
+) -> Result<Json<IngestResult>, AdminError> {
+    let Query(query) = query.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let app_name = query.app.as_deref().unwrap_or(DEFAULT_APP);
+    let app_cnf = crate::config::get()
+        .app(app_name)
+        .ok_or_else(|| AdminError::BadRequest(format!("no app named {app_name}")))?;
+    let result = QueueAdmin::new(app.queue_store.get()?.clone())
+        .ingest(app_cnf, &app.request_handle_svc, &query, &ndjson)
+        .await?;
+    Ok(Json(result))
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn list_pauses(
+    State(app): State<Arc<AppEnv>>,
+) -> Result<Json<Vec<PauseInfo>>, AdminError> {
+    Ok(Json(pause::list(&mut app.redis_conn.get()?.clone()).await?))
+}
+
+// body: {"shop": ..., "topic": ..., "reason": ...}, no shop pauses every queue
+#[tracing::instrument(level = "debug")]
+pub async fn pause_scope(
+    State(app): State<Arc<AppEnv>>,
+    request: Result<Json<PauseRequest>, JsonRejection>,
+) -> Result<Json<PauseInfo>, AdminError> {
+    let Json(request) = request.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let field = request
+        .scope
+        .field()
+        .map_err(|e| AdminError::BadRequest(e.to_string()))?;
+    let flag = pause::pause(&mut app.redis_conn.get()?.clone(), &field, request.reason).await?;
+    Ok(Json(PauseInfo {
+        shop: request.scope.shop,
+        topic: request.scope.topic,
+        flag,
+    }))
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn resume_scope(
+    State(app): State<Arc<AppEnv>>,
+    scope: Result<Query<PauseScope>, QueryRejection>,
+) -> Result<Response, AdminError> {
+    let Query(scope) = scope.map_err(|e| AdminError::BadRequest(e.body_text()))?;
+    let field = scope
+        .field()
+        .map_err(|e| AdminError::BadRequest(e.to_string()))?;
+    let status = if pause::resume(&mut app.redis_conn.get()?.clone(), &field).await? {
+        http::StatusCode::NO_CONTENT
+    } else {
+        http::StatusCode::NOT_FOUND
+    };
+    Ok(status.into_response())
+}
@@ -0,0 +1,123 @@
+use crate::app::AppEnv;
+use crate::model::health::{CheckResult, HealthReport, HealthStatus};
+use crate::services::health::{self, WorkerHealth};
+use anyhow::Context;
+use axum::extract::State;
+use axum::routing::get;
+use axum::Router;
+use rdkafka::producer::FutureProducer;
+use redis::aio::ConnectionManager;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+// liveness: the process is up and serving http
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+// readiness: the receiver can enqueue requests
+pub async fn readyz(State(app): State<Arc<AppEnv>>) -> HealthReport {
+    let redis = match app.redis_conn.get() {
+        Ok(redis_conn) => health::check_redis(redis_conn.clone()).await,
+        Err(_) => CheckResult::down("not connected yet"),
+    };
+    HealthReport::new(BTreeMap::from([("redis".to_string(), redis)]))
+}
+
+// what a background worker delivers to
+#[derive(Clone)]
+pub enum WorkerTarget {
+    Downstream(reqwest::Client),
+    Kafka(FutureProducer),
+}
+
+#[derive(Clone)]
+pub struct WorkerHealthState {
+    pub heartbeat: Arc<WorkerHealth>,
+    pub redis_conn: ConnectionManager,
+    pub target: WorkerTarget,
+}
+
+impl WorkerHealthState {
+    fn heartbeat_check(&self) -> (u64, CheckResult) {
+        let age = self.heartbeat.age();
+        let timeout = Duration::from_secs(crate::config::get().worker_heartbeat_timeout);
+        let check = if age > timeout {
+            CheckResult::down("stalled")
+        } else {
+            CheckResult {
+                status: HealthStatus::Ok,
+                latency_ms: None,
+                error: None,
+            }
+        };
+        (age.as_secs(), check)
+    }
+}
+
+async fn worker_healthz(State(state): State<WorkerHealthState>) -> HealthReport {
+    let (age, heartbeat) = state.heartbeat_check();
+    let mut report = HealthReport::new(BTreeMap::from([("heartbeat".to_string(), heartbeat)]));
+    report.heartbeat_age_secs = Some(age);
+    report
+}
+
+async fn worker_readyz(State(state): State<WorkerHealthState>) -> HealthReport {
+    let (age, heartbeat) = state.heartbeat_check();
+    let mut checks = BTreeMap::from([
+        ("heartbeat".to_string(), heartbeat),
+        (
+            "redis".to_string(),
+            health::check_redis(state.redis_conn.clone()).await,
+        ),
+    ]);
+    match &state.target {
+        WorkerTarget::Downstream(client) => {
+            for app in &crate::config::get().apps {
+                let check = health::check_http(client, &app.downstream_app_url).await;
+                checks.insert(format!("downstream:{}", app.name), check);
+            }
+        }
+        WorkerTarget::Kafka(producer) => {
+            checks.insert(
+                "kafka".to_string(),
+                health::check_kafka(producer.clone()).await,
+            );
+        }
+    }
+    let mut report = HealthReport::new(checks);
+    report.heartbeat_age_secs = Some(age);
+    report
+}
+
+// small listener of downstreamer (DOWNSTREAMER_HEALTH_ADDR) and kafka_producer
+// (KAFKA_PRODUCER_HEALTH_ADDR), stops with the worker. Binds before returning so a taken address
+// fails startup, None when `addr` is empty
+pub async fn serve_worker(
+    addr: &str,
+    state: WorkerHealthState,
+    cancel_token: CancellationToken,
+) -> anyhow::Result<Option<tokio::task::JoinHandle<()>>> {
+    if addr.is_empty() {
+        return Ok(None);
+    }
+    let router = Router::new()
+        .route("/healthz", get(worker_healthz))
+        .route("/readyz", get(worker_readyz))
+        .route("/metrics", get(super::metrics::metrics))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind health listener on {addr}"))?;
+    tracing::info!("health listener on {addr}");
+    Ok(Some(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router)
+            .with_graceful_shutdown(async move { cancel_token.cancelled().await })
+            .await
+        {
+            tracing::error!("health listener failed: {e}");
+        }
+    })))
+}
@@ -0,0 +1,8 @@
+use crate::common;
+use crate::model::error::AppError;
+
+// prometheus scrape endpoint, served by the receiver and by the workers' health listener
+#[tracing::instrument(level = "debug")]
+pub async fn metrics() -> Result<String, AppError> {
+    Ok(common::metrics::encode()?)
+}
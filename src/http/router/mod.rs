@@ -9,20 +9,98 @@ use tower_http::{
     compression::CompressionLayer, normalize_path::NormalizePathLayer, trace::TraceLayer,
 };
 
+mod admin;
+pub mod health;
+mod metrics;
 mod webhook;
 
 pub async fn new(app: AppEnv) -> Router {
     let app_state = Arc::new(app);
-    Router::new()
+    let mut router = Router::new()
         .route("/", get(|| async { "Hello!" }))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/metrics", get(metrics::metrics))
         // .route("/webhook", get(webhook::home))
+        .route("/webhook/:app", post(webhook::webhook_handler))
         .route("/webhook/:resource/:topic", post(webhook::webhook_handler))
-        .with_state(app_state)
-        .layer(
-            ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(CompressionLayer::new())
-                .layer(NormalizePathLayer::trim_trailing_slash())
-                .layer(Extension(())),
+        .route(
+            "/webhook/:app/:resource/:topic",
+            post(webhook::webhook_handler),
+        );
+    if !crate::config::get().admin_token.is_empty() {
+        router = router.nest("/admin", admin::router());
+    }
+    router.with_state(app_state).layer(
+        ServiceBuilder::new()
+            .layer(TraceLayer::new_for_http())
+            .layer(CompressionLayer::new())
+            .layer(NormalizePathLayer::trim_trailing_slash())
+            .layer(Extension(())),
+    )
+}
+
+// started while redis is down, the receiver serves anyway: webhooks are spooled until it answers
+#[tokio::test]
+async fn test_receiver_without_redis() {
+    use crate::common::consts;
+    use crate::services::connect::Connected;
+    use crate::services::ingest_rules::IngestRules;
+    use crate::services::load_shed::LoadShedder;
+    use crate::services::payload_schema::PayloadSchemas;
+    use crate::services::spool::Spool;
+    use crate::services::wh_req_handler::ProductServiceImpl;
+    use axum::body::Body;
+    use data_encoding::BASE64;
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    crate::config::init_test_env();
+    let app_cnf = crate::config::get()
+        .app(crate::config::DEFAULT_APP)
+        .unwrap();
+    let dir = std::env::temp_dir().join(format!("webhook-receiver-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let spool = Arc::new(Spool::open(&dir, 1 << 20).unwrap());
+    let store = Connected::pending();
+    let svc = ProductServiceImpl::new(
+        Connected::pending(),
+        store.clone(),
+        Arc::new(LoadShedder::new()),
+        Arc::new(IngestRules::default()),
+        Arc::new(PayloadSchemas::default()),
+        Some(spool.clone()),
+    );
+    let router = new(AppEnv::new(svc, Connected::pending(), store)).await;
+
+    let payload = r#"{"id":9079211262258}"#;
+    let key = ring::hmac::Key::new(
+        ring::hmac::HMAC_SHA256,
+        app_cnf.client_secrets[0].secret.as_bytes(),
+    );
+    let request = Request::post("/webhook/orders/create")
+        .header(http::header::HOST, "localhost")
+        .header(consts::XSHOPIFY_SHOP_DOMAIN, "a.myshopify.com")
+        .header(consts::XSHOPIFY_TOPIC, "orders/create")
+        .header(
+            consts::XSHOPIFY_TRIGGERED_AT,
+            chrono::Utc::now().to_rfc3339(),
+        )
+        .header(consts::XSHOPIFY_WEBHOOK_ID, "1")
+        .header(
+            consts::XSHOPIFY_HMAC_SHA256,
+            BASE64.encode(ring::hmac::sign(&key, payload.as_bytes()).as_ref()),
         )
+        .body(Body::from(payload))
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!spool.is_empty());
+
+    let response = router
+        .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    std::fs::remove_dir_all(&dir).unwrap();
 }
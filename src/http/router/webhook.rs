@@ -1,9 +1,12 @@
 use crate::app::AppEnv;
-use crate::model::error::AppError;
+use crate::common::metrics;
+use crate::config::{self, DEFAULT_APP};
+use crate::model::error::{AppError, WebhookError};
 use crate::model::ReqDownstream;
 use crate::services::i_wh_req_handler::IWebhookRequestHandleService;
 use crate::services::wh_req_handler::ProductServiceImpl;
-use axum::extract::{Host, Query, State};
+use axum::extract::{Host, Path, Query, State};
+use bytes::Bytes;
 use http::{HeaderMap, Method, Uri};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -15,30 +18,62 @@ pub async fn home() -> String {
 
 #[tracing::instrument(level = "debug")]
 #[axum::debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn webhook_handler(
     State(app): State<Arc<AppEnv<ProductServiceImpl>>>,
+    Path(params): Path<HashMap<String, String>>,
     headers: HeaderMap,
     method: Method,
     Host(host): Host,
     uri: Uri,
     Query(queries): Query<HashMap<String, String>>,
-    payload: String,
+    payload: Bytes,
     // request: Request<Body>,
 ) -> Result<String, AppError> {
+    // /webhook/:resource/:topic is served by the default app, /webhook/:app by any app whose
+    // provider tells the topic in the headers or body
+    let app_name = params.get("app").map_or(DEFAULT_APP, String::as_str);
+    let Some(app_cnf) = config::get().app(app_name) else {
+        // the name comes from the caller, keep it out of the labels
+        let err = WebhookError::UnknownApp(app_name.to_string());
+        metrics::WEBHOOKS_REJECTED
+            .with_label_values(&[metrics::UNKNOWN, err.reason(), metrics::UNKNOWN])
+            .inc();
+        return Err(err.into());
+    };
+    // the downstream keeps seeing the path it used to be called with
+    let endpoint = match params.get("app") {
+        Some(name) => uri
+            .to_string()
+            .replacen(&format!("/webhook/{name}"), "/webhook", 1),
+        None => uri.to_string(),
+    };
+
+    let path_topic = match (params.get("resource"), params.get("topic")) {
+        (Some(resource), Some(topic)) => Some(format!("{resource}/{topic}")),
+        _ => None,
+    };
     let request = ReqDownstream {
-        endpoint: uri.to_string(),
+        endpoint,
         method,
         headers,
         queries,
         payload,
+        key_id: None,
     };
 
     // tracing::debug!("Received request {request:#?}");
 
-    if let Err(err) = app.request_handle_svc.handle_webhook_request(request).await {
-        tracing::error!("error while handling request: {err:?}");
-        return Err(err.into());
+    match app
+        .request_handle_svc
+        .handle_webhook_request(app_cnf, request, path_topic)
+        .await
+    {
+        Ok(Some(reply)) => Ok(reply),
+        Ok(None) => Ok("webhook request enqueued for downstream".to_string()),
+        Err(err) => {
+            tracing::error!("error while handling request for app {app_name}: {err:?}");
+            Err(err.into())
+        }
     }
-
-    Ok("webhook request enqueued for downstream".to_string())
 }
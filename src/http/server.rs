@@ -0,0 +1,108 @@
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use super::listener::{Listener, Stream};
+use super::tls::TlsReloader;
+
+// clients that don't finish their handshake in time are dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// axum::serve only does plain tcp. Serves the router on every listener until `shutdown` is
+// cancelled, then stops accepting and waits for in-flight requests, like axum's graceful shutdown
+pub async fn serve(
+    listeners: Vec<Listener>,
+    router: Router,
+    tls: Option<Arc<TlsReloader>>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let connections = TaskTracker::new();
+    let accept_loops = listeners.into_iter().map(|listener| {
+        accept_loop(
+            listener,
+            router.clone(),
+            tls.clone(),
+            shutdown.clone(),
+            connections.clone(),
+        )
+    });
+    futures::future::join_all(accept_loops).await;
+    connections.close();
+    connections.wait().await;
+    Ok(())
+}
+
+async fn accept_loop(
+    listener: Listener,
+    router: Router,
+    tls: Option<Arc<TlsReloader>>,
+    shutdown: CancellationToken,
+    connections: TaskTracker,
+) {
+    let tls = match listener {
+        Listener::Tcp { tls: true, .. } => tls,
+        _ => None,
+    };
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // e.g. out of file descriptors, give other connections a chance to close
+                    tracing::error!("accept error: {e:?}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let acceptor = tls.as_ref().map(|tls| tls.acceptor());
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            match (stream, acceptor) {
+                (Stream::Tcp(stream), Some(acceptor)) => {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => serve_connection(stream, router, shutdown).await,
+                        Ok(Err(e)) => tracing::debug!("tls handshake with {peer} failed: {e:?}"),
+                        Err(_) => tracing::debug!("tls handshake with {peer} timed out"),
+                    }
+                }
+                (Stream::Tcp(stream), None) => serve_connection(stream, router, shutdown).await,
+                (Stream::Unix(stream), _) => serve_connection(stream, router, shutdown).await,
+            }
+        });
+    }
+}
+
+async fn serve_connection<I>(io: I, router: Router, shutdown: CancellationToken)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = Builder::new(TokioExecutor::new());
+    let conn =
+        builder.serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(router));
+    tokio::pin!(conn);
+    let mut shutting_down = false;
+    loop {
+        tokio::select! {
+            res = conn.as_mut() => {
+                if let Err(e) = res {
+                    tracing::debug!("connection error: {e:?}");
+                }
+                break;
+            }
+            _ = shutdown.cancelled(), if !shutting_down => {
+                conn.as_mut().graceful_shutdown();
+                shutting_down = true;
+            }
+        }
+    }
+}
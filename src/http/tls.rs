@@ -0,0 +1,108 @@
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+// the certificate new handshakes are made with. Reloading swaps it, connections already
+// established keep the one they were accepted with
+pub struct TlsReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<ServerConfig>>,
+    // of the cert and key files, as of the last (re)load
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl TlsReloader {
+    pub fn load(
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let config = server_config(&cert_path, &key_path)?;
+        let reloader = Self {
+            modified: Mutex::new(modified(&cert_path, &key_path)),
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(config)),
+        };
+        Ok(reloader)
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    // keeps serving the previous certificate when the new one can't be loaded
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = modified(&self.cert_path, &self.key_path);
+        let config = server_config(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        *self.modified.lock().unwrap() = modified;
+        tracing::info!("reloaded tls certificate {:?}", self.cert_path);
+        Ok(())
+    }
+
+    fn changed(&self) -> bool {
+        let modified = modified(&self.cert_path, &self.key_path);
+        modified.is_some() && modified != *self.modified.lock().unwrap()
+    }
+
+    // reloads on SIGHUP, and when the files' mtime changes (checked every `interval` seconds, 0
+    // to only reload on SIGHUP)
+    pub async fn watch(self: Arc<Self>, interval: u64, cancel_token: CancellationToken) {
+        let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                tracing::error!("unable to install SIGHUP handler, no tls reload: {e:?}");
+                return;
+            }
+        };
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => return,
+                _ = sighup.recv() => {
+                    tracing::info!("SIGHUP received, reloading tls certificate...");
+                }
+                _ = ticker.tick() => {
+                    if interval == 0 || !self.changed() {
+                        continue;
+                    }
+                    tracing::info!("tls certificate files changed, reloading...");
+                }
+            }
+            if let Err(e) = self.reload() {
+                tracing::error!("unable to reload tls certificate: {e:?}");
+            }
+        }
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((mtime(cert_path)?, mtime(key_path)?))
+}
+
+fn server_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates from {cert_path:?}"))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("failed to read private key from {key_path:?}"))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid certificate or private key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
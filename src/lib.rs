@@ -1,6 +1,3 @@
-#![feature(result_flattening)]
-#![feature(if_let_guard)]
-#![feature(let_chains)]
 pub mod adapter;
 pub mod app;
 pub mod common;
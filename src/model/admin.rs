@@ -0,0 +1,292 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::ReqDownstream;
+
+// one queue ZSET, as listed by the admin api
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueInfo {
+    pub queue: String,
+    pub app: Option<String>,
+    pub shop: Option<String>,
+    pub topic: Option<String>,
+    pub depth: u64,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QueueFilter {
+    pub app: Option<String>,
+    pub shop: Option<String>,
+    pub topic: Option<String>,
+}
+
+impl QueueFilter {
+    pub fn matches(&self, app: Option<&str>, shop: Option<&str>, topic: Option<&str>) -> bool {
+        let eq =
+            |want: &Option<String>, got: Option<&str>| want.is_none() || want.as_deref() == got;
+        eq(&self.app, app) && eq(&self.shop, shop) && eq(&self.topic, topic)
+    }
+}
+
+// a queued request, decoded when possible. Undecodable members are still listed so they can be
+// found and purged
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedEvent {
+    pub triggered_at: DateTime<Utc>,
+    pub envelope_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<ReqDownstream>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventQuery {
+    pub queue: String,
+    // triggered-at range, both ends included
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub offset: u64,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventPage {
+    pub queue: String,
+    pub events: Vec<QueuedEvent>,
+    // offset of the next page, none once the range is exhausted
+    pub next_offset: Option<u64>,
+}
+
+// a triggered-at range of a queue, both ends included
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TimeRange {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PurgeRequest {
+    pub queue: String,
+    #[serde(flatten)]
+    pub range: TimeRange,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PurgeResult {
+    pub queue: String,
+    pub matched: u64,
+    pub removed: u64,
+    pub dry_run: bool,
+}
+
+// moves every event of `from` into `to`, merged with what's already there. Scores (triggered-at)
+// are kept, an event in both keeps the earliest
+#[derive(Debug, Clone, Deserialize)]
+pub struct MoveRequest {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MoveResult {
+    pub from: String,
+    pub to: String,
+    // events in `from`, and in `to` before the move
+    pub moved: u64,
+    pub existing: u64,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportQuery {
+    pub queue: String,
+    #[serde(flatten)]
+    pub range: TimeRange,
+}
+
+// one line of an NDJSON export. Members that can't be decoded are exported as their raw
+// envelope, base64 encoded, so an export/import round trip never loses events
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedEvent {
+    pub queue: String,
+    // triggered-at in millis, the event's score
+    pub score: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<ReqDownstream>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImportQuery {
+    // imports every line into this queue instead of the one it was exported from
+    pub queue: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LineError {
+    // 1-based
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportResult {
+    // events added, or that would be with dry_run
+    pub imported: u64,
+    // events already in their queue, only known without dry_run
+    pub existing: u64,
+    pub errors: Vec<LineError>,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IngestQuery {
+    // whose secrets and queues the requests belong to, the default app if not given
+    pub app: Option<String>,
+    // checks each request's signature again, against the app's current secrets
+    #[serde(default)]
+    pub verify: bool,
+    // turns events away once they're too old for the receiver
+    #[serde(default)]
+    pub expire: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestStatus {
+    Accepted,
+    // the same envelope is already in its queue, or its event id was seen
+    Existing,
+    // by an ingest rule
+    Dropped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestLine {
+    // 1-based
+    pub line: usize,
+    pub status: IngestStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IngestResult {
+    // requests enqueued, or that would be with dry_run
+    pub accepted: u64,
+    // only known without dry_run
+    pub existing: u64,
+    #[serde(default)]
+    pub dropped: u64,
+    pub failed: u64,
+    pub lines: Vec<IngestLine>,
+    pub dry_run: bool,
+}
+
+// what a pause flag applies to, stored as the field of the pause flags hash: `*`, `{shop}` or
+// `{shop}:{topic}`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PauseScope {
+    pub shop: Option<String>,
+    pub topic: Option<String>,
+}
+
+impl PauseScope {
+    pub const GLOBAL: &'static str = "*";
+
+    pub fn field(&self) -> Result<String, &'static str> {
+        match (&self.shop, &self.topic) {
+            (None, None) => Ok(Self::GLOBAL.to_string()),
+            (Some(shop), None) => Ok(shop.clone()),
+            (Some(shop), Some(topic)) => Ok(format!("{shop}:{topic}")),
+            (None, Some(_)) => Err("a topic can only be paused for a given shop"),
+        }
+    }
+
+    pub fn from_field(field: &str) -> Self {
+        if field == Self::GLOBAL {
+            return Self {
+                shop: None,
+                topic: None,
+            };
+        }
+        match field.split_once(':') {
+            Some((shop, topic)) => Self {
+                shop: Some(shop.to_string()),
+                topic: Some(topic.to_string()),
+            },
+            None => Self {
+                shop: Some(field.to_string()),
+                topic: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PauseFlag {
+    pub paused_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PauseRequest {
+    #[serde(flatten)]
+    pub scope: PauseScope,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PauseInfo {
+    pub shop: Option<String>,
+    pub topic: Option<String>,
+    #[serde(flatten)]
+    pub flag: PauseFlag,
+}
+
+#[test]
+fn test_queue_filter() {
+    let filter: QueueFilter = serde_json::from_str(r#"{"shop":"a.myshopify.com"}"#).unwrap();
+    assert!(filter.matches(
+        Some("default"),
+        Some("a.myshopify.com"),
+        Some("orders/create")
+    ));
+    assert!(!filter.matches(
+        Some("default"),
+        Some("b.myshopify.com"),
+        Some("orders/create")
+    ));
+    // queues no app claims only match an empty filter
+    assert!(!filter.matches(None, None, None));
+    assert!(QueueFilter::default().matches(None, None, None));
+}
+
+#[test]
+fn test_pause_scope() {
+    for field in ["*", "a.myshopify.com", "a.myshopify.com:products/update"] {
+        assert_eq!(PauseScope::from_field(field).field().unwrap(), field);
+    }
+    let orphan_topic = PauseScope {
+        shop: None,
+        topic: Some("products/update".to_string()),
+    };
+    assert!(orphan_topic.field().is_err());
+}
@@ -1,11 +1,239 @@
 use axum::response::{IntoResponse, Response};
 use reqwest::StatusCode;
+use serde::Serialize;
 use thiserror::Error as ThisError;
 
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// RFC 7807 problem details, the body of every error response from the http layer
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ProblemDetails {
+    pub fn new(status: StatusCode, kind: &'static str, title: &'static str) -> Self {
+        Self {
+            kind,
+            title,
+            status: status.as_u16(),
+            detail: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+impl IntoResponse for ProblemDetails {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::to_vec(&self).unwrap_or_default();
+        (status, [(http::header::CONTENT_TYPE, PROBLEM_JSON)], body).into_response()
+    }
+}
+
+// failures of the ingestion path, each maps to a distinct status so shopify and our dashboards
+// can tell them apart
+#[derive(ThisError, Debug)]
+pub enum WebhookError {
+    #[error("no app named {0}")]
+    UnknownApp(String),
+    #[error("missing or malformed header {0}")]
+    MissingHeader(&'static str),
+    #[error("malformed payload: {0}")]
+    MalformedPayload(String),
+    #[error("hmac signature verification failed")]
+    BadSignature,
+    #[error("event triggered at {0} is too old to be accepted")]
+    ExpiredEvent(chrono::DateTime<chrono::Utc>),
+    #[error("rate limit exceeded, retry in {0} seconds")]
+    RateLimited(u64),
+    #[error("shedding load, retry in {0} seconds")]
+    Overloaded(u64),
+    #[error("topic {topic} sent to the route of {path_topic}")]
+    TopicMismatch { topic: String, path_topic: String },
+    #[error("refused by an ingest rule")]
+    Refused,
+    #[error("payload doesn't match the topic's schema: {0}")]
+    InvalidPayload(String),
+    #[error("queue storage unavailable: {0}")]
+    StorageUnavailable(#[from] redis::RedisError),
+    #[error("unable to encode request: {0}")]
+    Encoding(anyhow::Error),
+}
+
+impl WebhookError {
+    // short, bounded label for the rejection metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            WebhookError::UnknownApp(_) => "unknown_app",
+            WebhookError::MissingHeader(_) => "missing_header",
+            WebhookError::MalformedPayload(_) => "malformed_payload",
+            WebhookError::BadSignature => "bad_signature",
+            WebhookError::ExpiredEvent(_) => "expired_event",
+            WebhookError::RateLimited(_) => "rate_limited",
+            WebhookError::Overloaded(_) => "overloaded",
+            WebhookError::TopicMismatch { .. } => "topic_mismatch",
+            WebhookError::Refused => "refused",
+            WebhookError::InvalidPayload(_) => "invalid_payload",
+            WebhookError::StorageUnavailable(_) => "storage_unavailable",
+            WebhookError::Encoding(_) => "encoding",
+        }
+    }
+
+    pub fn problem(&self) -> ProblemDetails {
+        match self {
+            WebhookError::UnknownApp(_) => ProblemDetails::new(
+                StatusCode::NOT_FOUND,
+                "urn:webhook-svc:problem:unknown-app",
+                "Unknown app",
+            ),
+            WebhookError::MissingHeader(header) => ProblemDetails::new(
+                StatusCode::BAD_REQUEST,
+                "urn:webhook-svc:problem:missing-header",
+                "Missing or malformed header",
+            )
+            .with_detail(format!("header {header} is required")),
+            WebhookError::MalformedPayload(_) => ProblemDetails::new(
+                StatusCode::BAD_REQUEST,
+                "urn:webhook-svc:problem:malformed-payload",
+                "Malformed payload",
+            ),
+            WebhookError::BadSignature => ProblemDetails::new(
+                StatusCode::UNAUTHORIZED,
+                "urn:webhook-svc:problem:bad-signature",
+                "Signature verification failed",
+            ),
+            WebhookError::ExpiredEvent(_) => ProblemDetails::new(
+                StatusCode::GONE,
+                "urn:webhook-svc:problem:expired-event",
+                "Event too old",
+            ),
+            WebhookError::RateLimited(_) => ProblemDetails::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "urn:webhook-svc:problem:rate-limited",
+                "Too many webhooks for this shop",
+            ),
+            WebhookError::Overloaded(_) => ProblemDetails::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "urn:webhook-svc:problem:overloaded",
+                "Shedding load",
+            ),
+            WebhookError::TopicMismatch { topic, path_topic } => ProblemDetails::new(
+                StatusCode::BAD_REQUEST,
+                "urn:webhook-svc:problem:topic-mismatch",
+                "Topic doesn't match the route",
+            )
+            .with_detail(format!(
+                "{topic} webhooks don't belong to /webhook/{path_topic}"
+            )),
+            WebhookError::Refused => ProblemDetails::new(
+                StatusCode::FORBIDDEN,
+                "urn:webhook-svc:problem:refused",
+                "Refused by ingest rules",
+            ),
+            WebhookError::InvalidPayload(detail) => ProblemDetails::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "urn:webhook-svc:problem:invalid-payload",
+                "Payload doesn't match the topic's schema",
+            )
+            .with_detail(detail.clone()),
+            // don't leak the redis error to the caller, it's in the logs already
+            WebhookError::StorageUnavailable(_) => ProblemDetails::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "urn:webhook-svc:problem:storage-unavailable",
+                "Queue storage unavailable",
+            ),
+            WebhookError::Encoding(_) => internal_problem(),
+        }
+    }
+}
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> Response {
+        let mut resp = self.problem().into_response();
+        if let WebhookError::RateLimited(retry_after) | WebhookError::Overloaded(retry_after) = self
+        {
+            resp.headers_mut()
+                .insert(http::header::RETRY_AFTER, retry_after.into());
+        }
+        resp
+    }
+}
+
+// failures of the admin api
+#[derive(ThisError, Debug)]
+pub enum AdminError {
+    #[error("missing or invalid admin token")]
+    Unauthorized,
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("queue storage unavailable: {0}")]
+    StorageUnavailable(#[from] redis::RedisError),
+}
+
+impl AdminError {
+    pub fn problem(&self) -> ProblemDetails {
+        match self {
+            AdminError::Unauthorized => ProblemDetails::new(
+                StatusCode::UNAUTHORIZED,
+                "urn:webhook-svc:problem:unauthorized",
+                "Unauthorized",
+            ),
+            AdminError::BadRequest(detail) => ProblemDetails::new(
+                StatusCode::BAD_REQUEST,
+                "urn:webhook-svc:problem:bad-request",
+                "Bad request",
+            )
+            .with_detail(detail.clone()),
+            AdminError::Conflict(detail) => ProblemDetails::new(
+                StatusCode::CONFLICT,
+                "urn:webhook-svc:problem:conflict",
+                "Conflict",
+            )
+            .with_detail(detail.clone()),
+            AdminError::StorageUnavailable(_) => ProblemDetails::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "urn:webhook-svc:problem:storage-unavailable",
+                "Queue storage unavailable",
+            ),
+        }
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        if let AdminError::StorageUnavailable(e) = &self {
+            tracing::error!("admin request failed: {e:?}");
+        }
+        self.problem().into_response()
+    }
+}
+
+fn internal_problem() -> ProblemDetails {
+    ProblemDetails::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "about:blank",
+        "Internal Server Error",
+    )
+}
+
 #[derive(ThisError, Debug)]
 pub enum AppError {
     #[error("request not originated from shopify")]
     NotShopifyOriginated,
+    #[error("{0}")]
+    Webhook(#[from] WebhookError),
     #[error("some of the expected services are unavailable: {source}")]
     ServiceUnavailable {
         #[from]
@@ -23,7 +251,12 @@ pub enum AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", self)).into_response()
+        match self {
+            AppError::NotShopifyOriginated => WebhookError::BadSignature.into_response(),
+            AppError::Webhook(e) => e.into_response(),
+            // anything else is an internal failure, details stay in the logs
+            _ => internal_problem().into_response(),
+        }
     }
 }
 
@@ -72,3 +305,75 @@ impl From<(rdkafka::error::KafkaError, rdkafka::message::OwnedMessage)> for BgKa
         Self::KafkaError(e.0)
     }
 }
+
+#[test]
+fn test_webhook_error_status() {
+    let cases = [
+        (
+            WebhookError::UnknownApp("omega".to_string()),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            WebhookError::MissingHeader("x-shopify-topic"),
+            StatusCode::BAD_REQUEST,
+        ),
+        (WebhookError::BadSignature, StatusCode::UNAUTHORIZED),
+        (
+            WebhookError::ExpiredEvent(chrono::DateTime::<chrono::Utc>::MIN_UTC),
+            StatusCode::GONE,
+        ),
+        (WebhookError::RateLimited(2), StatusCode::TOO_MANY_REQUESTS),
+        (
+            WebhookError::Overloaded(60),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            WebhookError::TopicMismatch {
+                topic: "orders/create".to_string(),
+                path_topic: "products/update".to_string(),
+            },
+            StatusCode::BAD_REQUEST,
+        ),
+        (WebhookError::Refused, StatusCode::FORBIDDEN),
+        (
+            WebhookError::InvalidPayload("/id: \"1\" is not of type \"integer\"".to_string()),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            WebhookError::StorageUnavailable(redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "connection refused",
+            ))),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    ];
+    for (err, status) in cases {
+        let resp = AppError::from(err).into_response();
+        assert_eq!(resp.status(), status);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            PROBLEM_JSON
+        );
+    }
+    let resp = WebhookError::RateLimited(2).into_response();
+    assert_eq!(resp.headers().get(http::header::RETRY_AFTER).unwrap(), "2");
+}
+
+#[test]
+fn test_problem_hides_internal_details() {
+    let err = AppError::from(anyhow::anyhow!("redis://secret-host:6379 exploded"));
+    let resp = err.into_response();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let problem = serde_json::to_string(&internal_problem()).unwrap();
+    assert!(!problem.contains("secret-host"));
+    let problem = serde_json::to_value(
+        WebhookError::StorageUnavailable(redis::RedisError::from((
+            redis::ErrorKind::IoError,
+            "secret-host unreachable",
+        )))
+        .problem(),
+    )
+    .unwrap();
+    assert_eq!(problem["status"], 503);
+    assert!(!problem.to_string().contains("secret-host"));
+}
@@ -0,0 +1,96 @@
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// ordered from best to worst, a report is as bad as its worst check
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    // working, but slower than expected
+    Degraded,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    // short reason only, the full error goes to the logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+impl CheckResult {
+    pub fn down(error: &'static str) -> Self {
+        Self {
+            status: HealthStatus::Down,
+            latency_ms: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    // seconds since a background worker's loop last made progress
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_age_secs: Option<u64>,
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+impl HealthReport {
+    pub fn new(checks: BTreeMap<String, CheckResult>) -> Self {
+        let status = checks
+            .values()
+            .map(|c| c.status)
+            .max()
+            .unwrap_or(HealthStatus::Ok);
+        Self {
+            status,
+            heartbeat_age_secs: None,
+            checks,
+        }
+    }
+}
+
+impl IntoResponse for HealthReport {
+    fn into_response(self) -> Response {
+        let status = match self.status {
+            HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+            HealthStatus::Ok | HealthStatus::Degraded => StatusCode::OK,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[test]
+fn test_report_status() {
+    let ok = CheckResult {
+        status: HealthStatus::Ok,
+        latency_ms: Some(1),
+        error: None,
+    };
+    let slow = CheckResult {
+        status: HealthStatus::Degraded,
+        latency_ms: Some(500),
+        error: None,
+    };
+    let report = HealthReport::new(BTreeMap::from([
+        ("redis".to_string(), ok.clone()),
+        ("kafka".to_string(), slow),
+    ]));
+    assert_eq!(report.status, HealthStatus::Degraded);
+    assert_eq!(report.into_response().status(), StatusCode::OK);
+
+    let report = HealthReport::new(BTreeMap::from([
+        ("redis".to_string(), CheckResult::down("timeout")),
+        ("kafka".to_string(), ok),
+    ]));
+    assert_eq!(report.status, HealthStatus::Down);
+    assert_eq!(report.into_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+}
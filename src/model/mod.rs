@@ -4,7 +4,12 @@ use serde_json::value::RawValue;
 use serde_json::Value;
 use std::collections::HashMap;
 
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+
+pub mod admin;
 pub mod error;
+pub mod health;
 
 #[derive(Debug, Clone)]
 pub struct Product {
@@ -20,7 +25,145 @@ pub struct ReqDownstream {
     pub headers: http::HeaderMap,
     pub queries: HashMap<String, String>,
     // #[serde( serialize_with = "payload_serialize", deserialize_with = "payload_deserialize")]
-    pub payload: String,
+    #[serde(with = "payload_bytes")]
+    pub payload: Bytes,
+    // id of the client secret the request's signature was verified with
+    #[serde(default)]
+    pub key_id: Option<String>,
+}
+
+// upper bound of a decompressed envelope, guards against zstd bombs in the queues
+const ENVELOPE_MAX_SIZE: usize = 100000;
+// leading byte of an envelope, bumped whenever ReqDownstream's layout changes since bitcode isn't
+// self-describing. Unversioned envelopes start with zstd's magic number instead (0x28)
+const ENVELOPE_VERSION: u8 = 2;
+
+impl ReqDownstream {
+    // version byte + zstd-compressed bitcode, the form requests are stored in the redis queues
+    pub fn to_envelope(&self) -> anyhow::Result<Vec<u8>> {
+        let mut envelope = vec![ENVELOPE_VERSION];
+        envelope.extend(zstd::bulk::compress(&bitcode::serialize(self)?, 0)?);
+        Ok(envelope)
+    }
+
+    pub fn from_envelope(envelope: &[u8]) -> anyhow::Result<Self> {
+        match envelope.split_first() {
+            Some((&ENVELOPE_VERSION, compressed)) => Ok(bitcode::deserialize(
+                &zstd::bulk::decompress(compressed, ENVELOPE_MAX_SIZE)?,
+            )?),
+            _ => Self::from_unversioned_envelope(envelope),
+        }
+    }
+
+    // a queue member and its score, the time its event was triggered (in millis). The one decode
+    // path for everything reading the queues
+    pub fn from_queued(envelope: &[u8], score: f64) -> anyhow::Result<(Self, DateTime<Utc>)> {
+        let triggered_at = DateTime::<Utc>::from_timestamp_millis(score as i64)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        Ok((Self::from_envelope(envelope)?, triggered_at))
+    }
+
+    // enqueued before envelopes were versioned: first with the payload as raw bytes, and before
+    // that as a utf-8 string
+    fn from_unversioned_envelope(envelope: &[u8]) -> anyhow::Result<Self> {
+        let raw = zstd::bulk::decompress(envelope, ENVELOPE_MAX_SIZE)?;
+        match bitcode::deserialize::<LegacyReqDownstream<Vec<u8>>>(&raw) {
+            Ok(req) => Ok(req.into()),
+            Err(e) => bitcode::deserialize::<LegacyReqDownstream<String>>(&raw)
+                .map(Into::into)
+                .map_err(|_| e.into()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LegacyReqDownstream<P> {
+    endpoint: String,
+    #[serde(with = "http_serde::method")]
+    method: http::Method,
+    #[serde(with = "http_serde::header_map")]
+    headers: http::HeaderMap,
+    queries: HashMap<String, String>,
+    payload: P,
+}
+
+impl<P: Into<Bytes>> From<LegacyReqDownstream<P>> for ReqDownstream {
+    fn from(req: LegacyReqDownstream<P>) -> Self {
+        Self {
+            endpoint: req.endpoint,
+            method: req.method,
+            headers: req.headers,
+            queries: req.queries,
+            payload: req.payload.into(),
+            key_id: None,
+        }
+    }
+}
+
+// the payload is kept as raw bytes in the bitcode envelope. JSON consumers (kafka, the README
+// format) keep seeing it as a string, unless the body isn't valid utf-8, in which case it's
+// written as {"base64": "..."} so it still round-trips byte-for-byte
+mod payload_bytes {
+    use bytes::Bytes;
+    use data_encoding::BASE64;
+    use serde::de::{self, MapAccess, Visitor};
+    use serde::ser::SerializeMap;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &Bytes, s: S) -> Result<S::Ok, S::Error> {
+        if !s.is_human_readable() {
+            return s.serialize_bytes(payload);
+        }
+        match std::str::from_utf8(payload) {
+            Ok(text) => s.serialize_str(text),
+            Err(_) => {
+                let mut map = s.serialize_map(Some(1))?;
+                map.serialize_entry("base64", &BASE64.encode(payload))?;
+                map.end()
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Bytes, D::Error> {
+        struct PayloadVisitor;
+        impl<'de> Visitor<'de> for PayloadVisitor {
+            type Value = Bytes;
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a string, bytes or a {\"base64\": ...} object")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Bytes, E> {
+                Ok(Bytes::copy_from_slice(v.as_bytes()))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Bytes, E> {
+                Ok(Bytes::from(v))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+                Ok(Bytes::copy_from_slice(v))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                Ok(Bytes::from(v))
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Bytes, M::Error> {
+                match map.next_entry::<String, String>()? {
+                    Some((key, encoded)) if key == "base64" => BASE64
+                        .decode(encoded.as_bytes())
+                        .map(Bytes::from)
+                        .map_err(de::Error::custom),
+                    _ => Err(de::Error::missing_field("base64")),
+                }
+            }
+        }
+        if de.is_human_readable() {
+            de.deserialize_any(PayloadVisitor)
+        } else {
+            de.deserialize_byte_buf(PayloadVisitor)
+        }
+    }
 }
 
 // customized serialize/deserialize functions for the JSON payload, in case the JSON serialization
@@ -57,14 +200,16 @@ fn payload_deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<String, D::Er
 fn test_serialize() {
     use http::HeaderMap;
     let a = ReqDownstream {
-        payload: r#"{
+        payload: Bytes::from_static(
+            br#"{
            "foo": 2307
-        }"#
-        .to_string(),
+        }"#,
+        ),
         endpoint: "".to_string(),
         method: http::Method::GET,
         headers: HeaderMap::new(),
         queries: HashMap::new(),
+        key_id: Some("default".to_string()),
     };
     println!("a: {a:#?}");
 
@@ -79,3 +224,73 @@ fn test_serialize() {
     println!("bitc_a: {bitc_a:#?}");
     assert_eq!(a, bitc_a);
 }
+
+#[test]
+fn test_binary_payload_roundtrip() {
+    let a = ReqDownstream {
+        payload: Bytes::from_static(&[0xff, 0xfe, 0x00, b'{', 0x80]),
+        endpoint: "/webhook/products/update".to_string(),
+        method: http::Method::POST,
+        headers: http::HeaderMap::new(),
+        queries: HashMap::new(),
+        key_id: None,
+    };
+    let json = serde_json::to_value(&a).unwrap();
+    assert_eq!(json["payload"]["base64"], "//4Ae4A=");
+    assert_eq!(a, serde_json::from_value::<ReqDownstream>(json).unwrap());
+    assert_eq!(
+        a,
+        ReqDownstream::from_envelope(&a.to_envelope().unwrap()).unwrap()
+    );
+}
+
+#[test]
+fn test_legacy_envelope() {
+    #[derive(Serialize)]
+    struct Legacy<'a> {
+        endpoint: &'a str,
+        #[serde(with = "http_serde::method")]
+        method: http::Method,
+        #[serde(with = "http_serde::header_map")]
+        headers: http::HeaderMap,
+        queries: HashMap<String, String>,
+        payload: &'a str,
+    }
+    let mut headers = http::HeaderMap::new();
+    headers.insert("x-shopify-topic", "products/update".parse().unwrap());
+    let legacy = Legacy {
+        endpoint: "/webhook/products/update",
+        method: http::Method::POST,
+        headers: headers.clone(),
+        queries: HashMap::new(),
+        payload: r#"{"id":9079211262258}"#,
+    };
+    let envelope = zstd::bulk::compress(&bitcode::serialize(&legacy).unwrap(), 0).unwrap();
+    let req = ReqDownstream::from_envelope(&envelope).unwrap();
+    assert_eq!(req.endpoint, legacy.endpoint);
+    assert_eq!(req.headers, headers);
+    assert_eq!(req.payload, legacy.payload.as_bytes());
+    assert_eq!(req.key_id, None);
+
+    #[derive(Serialize)]
+    struct LegacyBytes<'a> {
+        endpoint: &'a str,
+        #[serde(with = "http_serde::method")]
+        method: http::Method,
+        #[serde(with = "http_serde::header_map")]
+        headers: http::HeaderMap,
+        queries: HashMap<String, String>,
+        payload: &'a [u8],
+    }
+    let legacy = LegacyBytes {
+        endpoint: "/webhook/products/update",
+        method: http::Method::POST,
+        headers: headers.clone(),
+        queries: HashMap::new(),
+        payload: &[0xff, 0x00, 0x7b],
+    };
+    let envelope = zstd::bulk::compress(&bitcode::serialize(&legacy).unwrap(), 0).unwrap();
+    let req = ReqDownstream::from_envelope(&envelope).unwrap();
+    assert_eq!(req.headers, headers);
+    assert_eq!(req.payload, legacy.payload);
+}
@@ -1,16 +1,17 @@
-use anyhow::{Context, Result};
-use chrono::{DateTime, TimeDelta, Utc};
-use redis::AsyncCommands;
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
 use std::ops::Sub;
 use std::sync::Arc;
 use std::time::Duration;
 
 use crate::app::BgWorker;
-use crate::common::consts;
+use crate::common::metrics;
+use crate::config::AppConfig;
 use crate::model::error::BgError;
 use crate::model::ReqDownstream;
+use crate::services::queue_store::{self, Claimed, QueueStore};
 
-impl BgWorker {
+impl<Store: QueueStore> BgWorker<Store> {
     #[tracing::instrument(level = "debug")]
     pub fn start_bg(self) -> anyhow::Result<tokio::task::JoinHandle<Result<()>>> {
         tracing::debug!("start BG worker");
@@ -36,13 +37,12 @@ impl BgWorker {
                     },
                     _ = timer => {
                         tracing::info!("bg worker woke up!");
-                        let mut redis_conn = selfp.redis_conn.clone();
-                        let mut cmd = redis::cmd("SCAN");
-                        cmd.arg(0).arg("TYPE").arg("ZSET"); // build Cmd
-                        let mut queues: redis::AsyncIter<String> = cmd.iter_async(&mut redis_conn).await?;
+                        selfp.health.beat();
+                        let pause_flags = selfp.pause_flags().await?;
+                        let queues = queue_store::deliverable(selfp.store.queues().await?, &pause_flags);
 
                         let mut handlers = vec![];
-                        while let Some(queue) = queues.next_item().await {
+                        for queue in queues {
                             tracing::info!("handling a batch of requests in {queue:?}");
                             let migrating_self = selfp.clone();
                             let handler = tokio::spawn(migrating_self.handle_queue(queue, batch_size));
@@ -59,49 +59,40 @@ impl BgWorker {
     }
 
     async fn handle_queue(self: Arc<Self>, queue: String, batch_size: u64) -> Result<()> {
-        let mut range_start = 0;
-        loop {
-            let queued_requests = self
-                .pull_requests(&queue, range_start, batch_size)
-                .await
-                .inspect_err(|e| tracing::error!("{e:?}"))?;
-            tracing::debug!("queued requests: {queued_requests:?}");
-            if queued_requests.is_empty() {
-                tracing::debug!("requests queue empty");
-                break;
-            }
-            range_start += batch_size;
-
-            for req in queued_requests {
-                let migrating_self = self.clone();
-                migrating_self.handle_request(&queue, &req).await?;
-            }
-        }
-        tracing::info!("finished handling requests in {queue:?}");
-        Ok(())
+        queue_store::drain(
+            &self.store,
+            &queue,
+            batch_size,
+            || async { Ok(self.pause_flags().await?.is_queue_paused(&queue)) },
+            |app, claimed| self.clone().handle_request(app, &queue, claimed),
+        )
+        .await
     }
 
-    async fn handle_request(self: Arc<Self>, queue: &str, req: &Vec<u8>) -> Result<()> {
+    async fn handle_request(
+        self: Arc<Self>,
+        app: &AppConfig,
+        queue: &str,
+        claimed: Claimed,
+    ) -> Result<()> {
         // tracing::debug!("processing request: {req:?} in queue {queue:?}");
         tracing::debug!("processing a request in queue {queue:?}");
-        let req_de: ReqDownstream = bitcode::deserialize(&zstd::bulk::decompress(req, 100000)?)?;
+        self.health.beat();
+        // the score is when the event was triggered, as told by the app's verifier at ingestion
+        let (req_de, triggered_at) = ReqDownstream::from_queued(&claimed.request, claimed.score)?;
+        tracing::debug!("request signed with key {:?}", req_de.key_id);
 
         // if request has been in queue for too long (> 5 days) without being delivered to downstream, it's usually better to just drop it
-        let triggered_at = req_de.headers.get(consts::XSHOPIFY_TRIGGERED_AT).map_or(
-            chrono::DateTime::<Utc>::MIN_UTC,
-            |h| {
-                let payload = h.to_str().unwrap_or("");
-                payload
-                    .parse::<DateTime<Utc>>()
-                    .unwrap_or(chrono::DateTime::<Utc>::MIN_UTC)
-            },
-        );
         // check timestamp, if too old => drop
         let now = Utc::now();
         let five_days_ago = now.sub(TimeDelta::try_days(5).unwrap());
         if triggered_at.lt(&five_days_ago) {
             tracing::debug!("request older than 5 days");
-            self.delete_request(queue, req.as_slice())
+            metrics::DROPPED_TOO_OLD
+                .with_label_values(&[&app.name])
+                .inc();
+            self.store
+                .ack(queue, &claimed)
                 .await
                 .inspect_err(|e| tracing::error!("{e:?}"))?;
             tracing::debug!("deleted request in queue {queue:?}");
@@ -114,30 +105,43 @@ impl BgWorker {
         for rep in 0..max_retry_attempts {
             tracing::debug!("attempt number {rep} to call downstream...");
             let start = std::time::Instant::now();
-            let downstream_response = self.push_downstream(&req_de).await;
+            let downstream_response = self.push_downstream(app, &req_de).await;
             // .inspect_err(|e| tracing::error!("{e:?}"))
             // .map_err(anyhow::Error::from)?;
             let elapsed = start.elapsed();
             tracing::debug!("elapsed time for request: {elapsed:?}");
+            metrics::DELIVERY_SECONDS
+                .with_label_values(&[&app.name])
+                .observe(elapsed.as_secs_f64());
 
             let next_delay: Duration;
             {
                 // to limit the scope of the mutex
-                let mut cc_state = self.cc_state.lock().await;
+                let mut cc_state = self.cc_states[&app.name].lock().await;
                 cc_state.update_cc_state(&elapsed); // update congestion control state
                 next_delay = cc_state.sleep_duration;
+                metrics::CC_RTT_MIN_SECONDS
+                    .with_label_values(&[&app.name])
+                    .set(cc_state.rtt_min().as_secs_f64());
+                metrics::CC_RTT_MAX_SECONDS
+                    .with_label_values(&[&app.name])
+                    .set(cc_state.rtt_max().as_secs_f64());
+                metrics::CC_SLEEP_SECONDS
+                    .with_label_values(&[&app.name])
+                    .set(next_delay.as_secs_f64());
             }
 
             if let Err(e) = &downstream_response {
                 tracing::error!("downstream requesing error: {e:?}");
-                match e {
+                let status_class = match e {
                     BgError::ReqwestError(re)
                         if let Some(status) = re.status()
                             && status.is_client_error() =>
                     {
                         if status == http::StatusCode::BAD_REQUEST {
                             tracing::debug!("removing faulty requests in queue {queue:?}");
-                            self.delete_request(queue, req.as_slice())
+                            self.store
+                                .ack(queue, &claimed)
                                 .await
                                 .inspect_err(|e| tracing::error!("{e:?}"))?;
                             break;
@@ -145,6 +149,7 @@ impl BgWorker {
                         tracing::debug!(
                             "downstream error {re:#?}, retrying in {retry_interval} seconds..."
                         );
+                        "4xx"
                     }
                     BgError::ReqwestError(re)
                         if let Some(status) = re.status()
@@ -154,12 +159,14 @@ impl BgWorker {
                         tracing::debug!(
                             "downstream error {re:#?}, retrying in {retry_interval} seconds..."
                         );
+                        "5xx"
                     }
                     BgError::ReqwestError(re) if re.is_timeout() => {
                         // retry logic
                         tracing::debug!(
                             "request timeout: {re:#?}, retrying in {retry_interval} seconds..."
                         );
+                        "timeout"
                     }
                     BgError::ReqwestError(re) => {
                         tracing::error!("unable to handle downstream request error: {re:?}");
@@ -170,6 +177,9 @@ impl BgWorker {
                         break;
                     }
                 };
+                metrics::DELIVERY_RETRIES
+                    .with_label_values(&[&app.name, status_class])
+                    .inc();
                 tracing::debug!("retrying...");
                 tokio::time::sleep(Duration::from_millis(500 * retry_interval)).await;
                 let tmp = retry_interval + next_retry;
@@ -179,7 +189,8 @@ impl BgWorker {
             } else {
                 tracing::debug!("ret = {downstream_response:?}");
                 tracing::debug!("pushed request");
-                self.delete_request(queue, req.as_slice())
+                self.store
+                    .ack(queue, &claimed)
                     .await
                     .inspect_err(|e| tracing::error!("{e:?}"))?;
                 tracing::debug!("deleted request in queue {queue:?}");
@@ -192,48 +203,27 @@ impl BgWorker {
         Ok(())
     }
 
-    //lul at the name, totally coindidental
     #[tracing::instrument(level = "debug")]
-    async fn pull_requests(
+    async fn push_downstream(
         &self,
-        queue_name: &str,
-        range_start: u64,
-        range_size: u64,
-    ) -> anyhow::Result<Vec<Vec<u8>>> {
-        let mut redis_conn = self.redis_conn.clone();
-        let rv: redis::RedisResult<Vec<Vec<u8>>> = redis_conn
-            .zrange(
-                queue_name,
-                range_start.try_into()?,
-                (range_start + range_size - 1).try_into()?,
-            )
-            .await;
-        Ok(rv?)
-    }
-
-    async fn delete_request(&self, queue_name: &str, member: &[u8]) -> anyhow::Result<()> {
-        let mut redis_conn = self.redis_conn.clone();
-        let rv: i32 = redis_conn
-            .zrem(queue_name, member)
-            .await
-            .context("failed to remove requests from {queue_name}")?;
-        tracing::debug!("removed an item {rv:?} in {queue_name}");
-        Ok(())
-    }
-
-    #[tracing::instrument(level = "debug")]
-    async fn push_downstream(&self, req: &ReqDownstream) -> Result<reqwest::Response, BgError> {
-        let base_url = &crate::config::get().downstream_app_url;
+        app: &AppConfig,
+        req: &ReqDownstream,
+    ) -> Result<reqwest::Response, BgError> {
+        let base_url = &app.downstream_app_url;
         let endpoint = &req.endpoint;
         let endpoint_url = format!("{base_url}{endpoint}");
         let url = reqwest::Url::parse(&endpoint_url).map_err(|e| BgError::ParseError(e.into()))?;
         tracing::debug!("url: {url}");
+        let mut headers = req.headers.to_owned();
+        crate::config::get()
+            .delivery_header_policy
+            .apply(app.provider, &mut headers);
         let resp = self
             .client
             .request(req.method.to_owned(), url)
-            .headers(req.headers.to_owned())
+            .headers(headers)
             .query(&req.queries)
-            .body(req.payload.to_owned())
+            .body(req.payload.clone())
             .send()
             .await
             .and_then(|r| r.error_for_status())
@@ -55,6 +55,14 @@ impl CongestionControlState {
         let sleep_duration = elapsed_scale * delaying_gain;
         self.sleep_duration = base_delay + Duration::from_micros(sleep_duration as u64);
     }
+
+    pub fn rtt_min(&self) -> Duration {
+        self.rtt_min
+    }
+
+    pub fn rtt_max(&self) -> Duration {
+        self.rtt_max
+    }
 }
 
 impl Default for CongestionControlState {
@@ -0,0 +1,77 @@
+use redis::aio::ConnectionManager;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+// one attempt, a redis that doesn't answer at all mustn't hold up the retries
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// what needs redis to have answered once. The receiver starts without it: until it's set
+// webhooks are spooled (SPOOL_DIR) or turned away and readiness fails
+pub struct Connected<T>(Arc<OnceLock<T>>);
+
+impl<T> Connected<T> {
+    pub fn pending() -> Self {
+        Self(Arc::new(OnceLock::new()))
+    }
+
+    pub fn ready(value: T) -> Self {
+        let connected = Self::pending();
+        connected.set(value);
+        connected
+    }
+
+    // only the first one sticks
+    pub fn set(&self, value: T) {
+        let _ = self.0.set(value);
+    }
+
+    // an io error while unset, so that `spool::unreachable` takes it for redis being down
+    pub fn get(&self) -> redis::RedisResult<&T> {
+        self.0.get().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "redis hasn't answered yet",
+            )
+            .into()
+        })
+    }
+}
+
+impl<T> Clone for Connected<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+// retries until redis answers, backing off up to 30s between attempts
+pub async fn connect(redis_cli: redis::Client) -> ConnectionManager {
+    let mut backoff = Duration::from_millis(500);
+    loop {
+        match tokio::time::timeout(CONNECT_TIMEOUT, ConnectionManager::new(redis_cli.clone())).await
+        {
+            Ok(Ok(redis_conn)) => return redis_conn,
+            Ok(Err(e)) => {
+                tracing::warn!("unable to connect to redis, retrying in {backoff:?}: {e:?}")
+            }
+            Err(_) => tracing::warn!("no answer from redis, retrying in {backoff:?}"),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[tokio::test]
+async fn test_connect_retries() {
+    // nothing listens on port 1
+    let redis_cli = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let attempt = tokio::time::timeout(Duration::from_secs(2), connect(redis_cli)).await;
+    assert!(attempt.is_err());
+
+    let connected = Connected::pending();
+    let err = connected.get().err().unwrap();
+    assert!(super::spool::unreachable(&err));
+    connected.clone().set(1);
+    connected.set(2);
+    assert_eq!(connected.get().ok(), Some(&1));
+}
@@ -0,0 +1,99 @@
+use rdkafka::producer::{FutureProducer, Producer};
+use redis::aio::ConnectionManager;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::model::health::{CheckResult, HealthStatus};
+
+// last time a background worker's loop made progress, shared with its health listener
+#[derive(Debug)]
+pub struct WorkerHealth {
+    last_beat_ms: AtomicI64,
+}
+
+impl WorkerHealth {
+    pub fn new() -> Self {
+        Self {
+            last_beat_ms: AtomicI64::new(chrono::Utc::now().timestamp_millis()),
+        }
+    }
+
+    pub fn beat(&self) {
+        self.last_beat_ms
+            .store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn age(&self) -> Duration {
+        let elapsed =
+            chrono::Utc::now().timestamp_millis() - self.last_beat_ms.load(Ordering::Relaxed);
+        Duration::from_millis(elapsed.max(0) as u64)
+    }
+}
+
+impl Default for WorkerHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// runs a dependency check under HEALTH_TIMEOUT_MS, slower than HEALTH_SLOW_MS is degraded
+async fn timed<T, E: Debug>(name: &str, check: impl Future<Output = Result<T, E>>) -> CheckResult {
+    let cnf = crate::config::get();
+    let start = Instant::now();
+    match tokio::time::timeout(Duration::from_millis(cnf.health_timeout_ms), check).await {
+        Err(_) => {
+            tracing::warn!("{name} health check timed out");
+            CheckResult::down("timeout")
+        }
+        Ok(Err(e)) => {
+            tracing::warn!("{name} health check failed: {e:?}");
+            CheckResult::down("unreachable")
+        }
+        Ok(Ok(_)) => {
+            let elapsed = start.elapsed();
+            let status = if elapsed > Duration::from_millis(cnf.health_slow_ms) {
+                HealthStatus::Degraded
+            } else {
+                HealthStatus::Ok
+            };
+            CheckResult {
+                status,
+                latency_ms: Some(elapsed.as_millis() as u64),
+                error: None,
+            }
+        }
+    }
+}
+
+pub async fn check_redis(mut redis_conn: ConnectionManager) -> CheckResult {
+    timed("redis", async move {
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut redis_conn)
+            .await
+    })
+    .await
+}
+
+// any http response counts, only connection failures and timeouts don't
+pub async fn check_http(client: &reqwest::Client, url: &str) -> CheckResult {
+    timed(url, client.head(url).send()).await
+}
+
+pub async fn check_kafka(producer: FutureProducer) -> CheckResult {
+    let timeout = Duration::from_millis(crate::config::get().health_timeout_ms);
+    timed("kafka", async move {
+        tokio::task::spawn_blocking(move || {
+            producer
+                .client()
+                .fetch_metadata(None, timeout)
+                .map(|_| ())
+                .map_err(anyhow::Error::from)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .flatten()
+    })
+    .await
+}
@@ -1,8 +1,14 @@
+use crate::config::AppConfig;
+use crate::model::error::WebhookError;
 use crate::model::ReqDownstream;
 
 pub trait IWebhookRequestHandleService: Send + Sync + 'static {
     fn handle_webhook_request(
         &self,
+        app: &AppConfig,
         request: ReqDownstream,
-    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+        // {resource}/{topic} of the route the request came in on, if it has them
+        path_topic: Option<String>,
+        // the reply the provider expects instead of the usual one, e.g. a slack challenge
+    ) -> impl Future<Output = Result<Option<String>, WebhookError>> + Send;
 }
@@ -0,0 +1,213 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    #[default]
+    Accept,
+    // acknowledged so the provider doesn't retry, but never enqueued
+    Drop,
+    Reject,
+}
+
+// matches when every criterion given matches. Patterns are exact, or end with `*` to match a
+// prefix (e.g. "orders/*"), `*` alone matches anything
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Rule {
+    pub action: RuleAction,
+    #[serde(default)]
+    pub apps: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub shops: Vec<String>,
+    // x-shopify-api-version, webhooks without one only match rules that don't set it
+    #[serde(default)]
+    pub api_versions: Vec<String>,
+}
+
+// first matching rule wins, `default` applies when none does
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RuleSet {
+    #[serde(default)]
+    pub default: RuleAction,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+fn matches(patterns: &[String], value: Option<&str>) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    let Some(value) = value else {
+        return false;
+    };
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => pattern == value,
+        })
+}
+
+impl Rule {
+    fn matches(&self, app: &str, topic: &str, shop: &str, api_version: Option<&str>) -> bool {
+        matches(&self.apps, Some(app))
+            && matches(&self.topics, Some(topic))
+            && matches(&self.shops, Some(shop))
+            && matches(&self.api_versions, api_version)
+    }
+}
+
+impl RuleSet {
+    pub fn action(
+        &self,
+        app: &str,
+        topic: &str,
+        shop: &str,
+        api_version: Option<&str>,
+    ) -> RuleAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(app, topic, shop, api_version))
+            .map_or(self.default, |rule| rule.action)
+    }
+}
+
+// the rules of INGEST_RULES_PATH as of their last (re)load. Without a file, everything is
+// accepted
+#[derive(Debug, Default)]
+pub struct IngestRules {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<RuleSet>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl IngestRules {
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let rules = read_rules(&path)?;
+        Ok(Self {
+            modified: Mutex::new(modified(&path)),
+            path: Some(path),
+            current: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<RuleSet> {
+        self.current.read().unwrap().clone()
+    }
+
+    // keeps the previous rules when the file can't be loaded
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let modified = modified(path);
+        let rules = read_rules(path)?;
+        tracing::info!("reloaded {} ingest rules from {path:?}", rules.rules.len());
+        *self.current.write().unwrap() = Arc::new(rules);
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    fn changed(&self) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+        let modified = modified(path);
+        modified.is_some() && modified != *self.modified.lock().unwrap()
+    }
+
+    // reloads on SIGHUP, and when the file's mtime changes (checked every `interval` seconds, 0
+    // to only reload on SIGHUP). Each receiver process reloads on its own
+    pub async fn watch(self: Arc<Self>, interval: u64) {
+        if self.path.is_none() {
+            return;
+        }
+        let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                tracing::error!("unable to install SIGHUP handler, no ingest rules reload: {e:?}");
+                return;
+            }
+        };
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+        loop {
+            tokio::select! {
+                _ = sighup.recv() => {}
+                _ = ticker.tick() => {
+                    if interval == 0 || !self.changed() {
+                        continue;
+                    }
+                }
+            }
+            if let Err(e) = self.reload() {
+                tracing::error!("unable to reload ingest rules: {e:?}");
+            }
+        }
+    }
+}
+
+fn modified(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_rules(path: &std::path::Path) -> anyhow::Result<RuleSet> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read ingest rules from {path:?}"))?;
+    serde_json::from_str(&raw).with_context(|| format!("invalid ingest rules in {path:?}"))
+}
+
+#[test]
+fn test_rule_action() {
+    let rules: RuleSet = serde_json::from_str(
+        r#"{
+            "rules": [
+                {"action": "accept", "topics": ["products/update"], "shops": ["a.myshopify.com"]},
+                {"action": "drop", "topics": ["products/*", "collections/update"]},
+                {"action": "reject", "api_versions": ["2022-*"]}
+            ]
+        }"#,
+    )
+    .unwrap();
+    let action = |topic, shop, api_version| rules.action("default", topic, shop, api_version);
+    assert_eq!(
+        action("products/update", "a.myshopify.com", None),
+        RuleAction::Accept
+    );
+    assert_eq!(
+        action("products/update", "b.myshopify.com", None),
+        RuleAction::Drop
+    );
+    assert_eq!(
+        action("collections/update", "b.myshopify.com", None),
+        RuleAction::Drop
+    );
+    assert_eq!(
+        action("orders/create", "b.myshopify.com", Some("2022-10")),
+        RuleAction::Reject
+    );
+    assert_eq!(
+        action("orders/create", "b.myshopify.com", None),
+        RuleAction::Accept
+    );
+
+    let allowlist: RuleSet = serde_json::from_str(
+        r#"{"default": "drop", "rules": [{"action": "accept", "topics": ["orders/create"]}]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        allowlist.action("default", "orders/create", "a.myshopify.com", None),
+        RuleAction::Accept
+    );
+    assert_eq!(
+        allowlist.action("default", "orders/updated", "a.myshopify.com", None),
+        RuleAction::Drop
+    );
+}
@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use redis::aio::ConnectionManager;
+
+use crate::common::metrics;
+use crate::config::{self, QueueBackend};
+use crate::model::error::WebhookError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LoadLevel {
+    Normal = 0,
+    // low priority topics are turned away
+    Soft = 1,
+    // only must-accept topics get in
+    Hard = 2,
+}
+
+impl LoadLevel {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => LoadLevel::Normal,
+            1 => LoadLevel::Soft,
+            _ => LoadLevel::Hard,
+        }
+    }
+
+    // the highest level whose limit (0 = none) is reached
+    fn of(value: u64, soft: u64, hard: u64) -> Self {
+        if hard > 0 && value >= hard {
+            LoadLevel::Hard
+        } else if soft > 0 && value >= soft {
+            LoadLevel::Soft
+        } else {
+            LoadLevel::Normal
+        }
+    }
+}
+
+// how loaded redis is, as of the last sample taken by `watch`. Checked on every webhook, so it's
+// only an atomic read
+#[derive(Debug, Default)]
+pub struct LoadShedder {
+    level: AtomicU8,
+}
+
+impl LoadShedder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn level(&self) -> LoadLevel {
+        LoadLevel::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    pub fn check(&self, topic: &str) -> Result<(), WebhookError> {
+        let cnf = config::get();
+        let shed = match self.level() {
+            LoadLevel::Normal => false,
+            LoadLevel::Soft => cnf.shed_low_priority_topics.iter().any(|t| t == topic),
+            LoadLevel::Hard => !cnf.shed_must_accept_topics.iter().any(|t| t == topic),
+        };
+        if shed {
+            return Err(WebhookError::Overloaded(cnf.shed_retry_after));
+        }
+        Ok(())
+    }
+
+    // samples the backlog and redis' memory every SHED_CHECK_INTERVAL seconds, for as long as the
+    // receiver runs. Failed samples leave the level as it was
+    pub async fn watch(&self, mut redis_conn: ConnectionManager) {
+        let cnf = config::get();
+        if cnf.shed_soft_backlog == 0
+            && cnf.shed_hard_backlog == 0
+            && cnf.shed_soft_memory == 0
+            && cnf.shed_hard_memory == 0
+        {
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(cnf.shed_check_interval));
+        loop {
+            interval.tick().await;
+            match sample(&mut redis_conn).await {
+                Ok((backlog, used_memory)) => {
+                    let level = std::cmp::max(
+                        LoadLevel::of(backlog, cnf.shed_soft_backlog, cnf.shed_hard_backlog),
+                        LoadLevel::of(used_memory, cnf.shed_soft_memory, cnf.shed_hard_memory),
+                    );
+                    let previous = self.level.swap(level as u8, Ordering::Relaxed);
+                    if previous != level as u8 {
+                        tracing::warn!(
+                            "load level now {level:?} (backlog {backlog}, used_memory {used_memory})"
+                        );
+                    }
+                    metrics::LOAD_LEVEL.set(level as i64);
+                    metrics::BACKLOG.set(backlog as i64);
+                    metrics::REDIS_USED_MEMORY_BYTES.set(used_memory as i64);
+                }
+                Err(e) => tracing::warn!("unable to sample redis load: {e:?}"),
+            }
+        }
+    }
+}
+
+// (queued requests across every queue, redis' used_memory)
+async fn sample(redis_conn: &mut ConnectionManager) -> anyhow::Result<(u64, u64)> {
+    let (key_type, len) = match config::get().queue_backend {
+        QueueBackend::Zset => ("ZSET", "ZCARD"),
+        QueueBackend::Stream => ("STREAM", "XLEN"),
+    };
+    let mut cmd = redis::cmd("SCAN");
+    cmd.arg(0).arg("TYPE").arg(key_type);
+    let mut pipe = redis::pipe();
+    {
+        let mut queues: redis::AsyncIter<String> = cmd.iter_async(redis_conn).await?;
+        while let Some(queue) = queues.next_item().await {
+            if !config::get().is_quarantine_queue(&queue) {
+                pipe.cmd(len).arg(queue);
+            }
+        }
+    }
+    let depths: Vec<u64> = pipe.query_async(redis_conn).await?;
+    let info: String = redis::cmd("INFO")
+        .arg("memory")
+        .query_async(redis_conn)
+        .await?;
+    let used_memory =
+        parse_used_memory(&info).ok_or_else(|| anyhow::anyhow!("no used_memory in INFO memory"))?;
+    Ok((depths.into_iter().sum(), used_memory))
+}
+
+fn parse_used_memory(info: &str) -> Option<u64> {
+    info.lines()
+        .find_map(|line| line.strip_prefix("used_memory:"))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+#[test]
+fn test_load_level() {
+    assert_eq!(LoadLevel::of(10, 0, 0), LoadLevel::Normal);
+    assert_eq!(LoadLevel::of(10, 10, 20), LoadLevel::Soft);
+    assert_eq!(LoadLevel::of(25, 10, 20), LoadLevel::Hard);
+    assert_eq!(LoadLevel::of(25, 0, 20), LoadLevel::Hard);
+    let info = "# Memory\r\nused_memory:1048576\r\nused_memory_human:1.00M\r\n";
+    assert_eq!(parse_used_memory(info), Some(1048576));
+}
@@ -1,5 +1,26 @@
 pub mod bg_kafka_worker_ext;
 pub mod bg_worker_ext;
 pub mod congestion_control;
+pub mod connect;
+pub mod health;
 pub mod i_wh_req_handler;
+pub mod ingest_rules;
+pub mod load_shed;
+pub mod pause;
+pub mod payload_schema;
+pub mod queue_admin;
+pub mod queue_store;
+pub mod rate_limit;
+pub mod spool;
+pub mod verifier;
 pub mod wh_req_handler;
+pub mod write_coalescer;
+
+// the redis of the #[ignore]d tests (cargo test -- --ignored), REDIS_URL or a local one. Each
+// test keeps to keys of its own
+#[cfg(test)]
+pub(crate) async fn test_redis_conn() -> redis::aio::ConnectionManager {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let client = redis::Client::open(url).unwrap();
+    redis::aio::ConnectionManager::new(client).await.unwrap()
+}
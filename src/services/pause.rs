@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use chrono::Utc;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::common::consts;
+use crate::config;
+use crate::model::admin::{PauseFlag, PauseInfo, PauseScope};
+
+// the paused scopes as of their last load. Ingestion ignores them, only the workers stop picking
+// the matching queues
+#[derive(Debug, Clone, Default)]
+pub struct PauseFlags {
+    scopes: HashSet<String>,
+}
+
+impl PauseFlags {
+    pub async fn load(redis_conn: &mut ConnectionManager) -> redis::RedisResult<Self> {
+        let scopes: HashSet<String> = redis_conn.hkeys(consts::PAUSE_FLAGS_KEY).await?;
+        Ok(Self { scopes })
+    }
+
+    pub fn is_paused(&self, shop: &str, topic: &str) -> bool {
+        self.scopes.contains(PauseScope::GLOBAL)
+            || self.scopes.contains(shop)
+            || self.scopes.contains(&format!("{shop}:{topic}"))
+    }
+
+    // queues no app claims can only be paused globally
+    pub fn is_queue_paused(&self, queue: &str) -> bool {
+        match config::get()
+            .app_for_queue(queue)
+            .and_then(|app| app.parse_queue_key(queue))
+        {
+            Some((shop, topic)) => self.is_paused(shop, topic),
+            None => self.scopes.contains(PauseScope::GLOBAL),
+        }
+    }
+}
+
+pub async fn list(redis_conn: &mut ConnectionManager) -> redis::RedisResult<Vec<PauseInfo>> {
+    let flags: Vec<(String, String)> = redis_conn.hgetall(consts::PAUSE_FLAGS_KEY).await?;
+    let mut pauses: Vec<PauseInfo> = flags
+        .into_iter()
+        .map(|(field, flag)| {
+            let scope = PauseScope::from_field(&field);
+            // flags set by hand (e.g. HSET pause-flags * 1) still count
+            let flag = match serde_json::from_str(&flag) {
+                Ok(flag) => flag,
+                Err(_) => PauseFlag {
+                    paused_at: chrono::DateTime::<Utc>::MIN_UTC,
+                    reason: Some(flag),
+                },
+            };
+            PauseInfo {
+                shop: scope.shop,
+                topic: scope.topic,
+                flag,
+            }
+        })
+        .collect();
+    pauses.sort_by(|a, b| (&a.shop, &a.topic).cmp(&(&b.shop, &b.topic)));
+    Ok(pauses)
+}
+
+pub async fn pause(
+    redis_conn: &mut ConnectionManager,
+    field: &str,
+    reason: Option<String>,
+) -> redis::RedisResult<PauseFlag> {
+    let flag = PauseFlag {
+        paused_at: Utc::now(),
+        reason,
+    };
+    let value = serde_json::to_string(&flag).expect("pause flags are serializable");
+    redis_conn
+        .hset::<_, _, _, ()>(consts::PAUSE_FLAGS_KEY, field, value)
+        .await?;
+    tracing::info!("paused {field:?}: {flag:?}");
+    Ok(flag)
+}
+
+// false when the scope wasn't paused
+pub async fn resume(redis_conn: &mut ConnectionManager, field: &str) -> redis::RedisResult<bool> {
+    let removed: u64 = redis_conn.hdel(consts::PAUSE_FLAGS_KEY, field).await?;
+    tracing::info!("resumed {field:?}");
+    Ok(removed > 0)
+}
+
+#[test]
+fn test_is_paused() {
+    let flags = |scopes: &[&str]| PauseFlags {
+        scopes: scopes.iter().map(|s| s.to_string()).collect(),
+    };
+    let shop = "a.myshopify.com";
+    assert!(!flags(&[]).is_paused(shop, "products/update"));
+    assert!(flags(&["*"]).is_paused(shop, "products/update"));
+    assert!(flags(&[shop]).is_paused(shop, "orders/create"));
+    let topic_only = flags(&["a.myshopify.com:products/update"]);
+    assert!(topic_only.is_paused(shop, "products/update"));
+    assert!(!topic_only.is_paused(shop, "orders/create"));
+    assert!(!topic_only.is_paused("b.myshopify.com", "products/update"));
+}
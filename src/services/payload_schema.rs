@@ -0,0 +1,122 @@
+use anyhow::{anyhow, Context};
+use jsonschema::JSONSchema;
+use std::collections::HashMap;
+
+use crate::config::{PayloadSchema, SchemaFailure};
+
+// failures kept per payload, the rest are dropped
+const MAX_ERRORS: usize = 10;
+// schema_path of payloads that aren't json at all
+pub const NOT_JSON: &str = "not_json";
+
+struct TopicSchema {
+    schema: JSONSchema,
+    on_failure: SchemaFailure,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaError {
+    // of the keyword that failed, e.g. /properties/id/type. Bounded by the schema, so fit for
+    // metric labels
+    pub schema_path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.schema_path)
+    }
+}
+
+// the PAYLOAD_SCHEMAS, compiled once at startup
+#[derive(Default)]
+pub struct PayloadSchemas {
+    by_topic: HashMap<String, TopicSchema>,
+}
+
+impl PayloadSchemas {
+    pub fn load(schemas: &[PayloadSchema]) -> anyhow::Result<Self> {
+        let mut by_topic = HashMap::new();
+        for cnf in schemas {
+            let raw = std::fs::read_to_string(&cnf.path)
+                .with_context(|| format!("failed to read schema {:?}", cnf.path))?;
+            let value: serde_json::Value = serde_json::from_str(&raw)
+                .with_context(|| format!("schema {:?} isn't json", cnf.path))?;
+            let schema = JSONSchema::compile(&value)
+                .map_err(|e| anyhow!("invalid schema {:?}: {e}", cnf.path))?;
+            let topic_schema = TopicSchema {
+                schema,
+                on_failure: cnf.on_failure,
+            };
+            if by_topic.insert(cnf.topic.clone(), topic_schema).is_some() {
+                return Err(anyhow!("more than one schema for topic {}", cnf.topic));
+            }
+        }
+        Ok(Self { by_topic })
+    }
+
+    // None when the payload matches its topic's schema, or the topic has none
+    pub fn validate(
+        &self,
+        topic: &str,
+        payload: &[u8],
+    ) -> Option<(SchemaFailure, Vec<SchemaError>)> {
+        let topic_schema = self.by_topic.get(topic)?;
+        let instance: serde_json::Value = match serde_json::from_slice(payload) {
+            Ok(instance) => instance,
+            Err(e) => {
+                let error = SchemaError {
+                    schema_path: NOT_JSON.to_string(),
+                    message: e.to_string(),
+                };
+                return Some((topic_schema.on_failure, vec![error]));
+            }
+        };
+        let errors: Vec<SchemaError> = match topic_schema.schema.validate(&instance) {
+            Ok(()) => return None,
+            Err(errors) => errors
+                .take(MAX_ERRORS)
+                .map(|e| SchemaError {
+                    schema_path: e.schema_path.to_string(),
+                    message: format!("{}: {e}", e.instance_path),
+                })
+                .collect(),
+        };
+        Some((topic_schema.on_failure, errors))
+    }
+}
+
+impl std::fmt::Debug for PayloadSchemas {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PayloadSchemas")
+            .field("topics", &self.by_topic.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[test]
+fn test_validate() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "required": ["id"],
+        "properties": {"id": {"type": "integer"}}
+    });
+    let schemas = PayloadSchemas {
+        by_topic: HashMap::from([(
+            "orders/create".to_string(),
+            TopicSchema {
+                schema: JSONSchema::compile(&schema).unwrap(),
+                on_failure: SchemaFailure::Quarantine,
+            },
+        )]),
+    };
+    assert_eq!(schemas.validate("orders/create", br#"{"id":1}"#), None);
+    assert_eq!(schemas.validate("orders/updated", b"{}"), None);
+    let (on_failure, errors) = schemas.validate("orders/create", br#"{"id":"1"}"#).unwrap();
+    assert_eq!(on_failure, SchemaFailure::Quarantine);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].schema_path, "/properties/id/type");
+    assert!(errors[0].message.starts_with("/id: "));
+    let (_, errors) = schemas.validate("orders/create", b"not json").unwrap();
+    assert_eq!(errors[0].schema_path, NOT_JSON);
+}
@@ -0,0 +1,610 @@
+use data_encoding::BASE64;
+
+use crate::config::{self, AppConfig};
+use crate::model::admin::{
+    EventPage, EventQuery, ExportQuery, ExportedEvent, ImportQuery, ImportResult, IngestLine,
+    IngestQuery, IngestResult, IngestStatus, LineError, MoveRequest, MoveResult, PurgeRequest,
+    PurgeResult, QueueFilter, QueueInfo, QueuedEvent, TimeRange,
+};
+use crate::model::ReqDownstream;
+use crate::services::queue_store::{AnyQueueStore, Enqueue, QueueStore, ScoreRange};
+use crate::services::verifier;
+use crate::services::wh_req_handler::{check_age, route_topic, Prepared, ProductServiceImpl};
+
+const DEFAULT_PAGE_SIZE: u64 = 50;
+const MAX_PAGE_SIZE: u64 = 500;
+// events per round trip of exports and imports
+const TRANSFER_BATCH_SIZE: usize = 500;
+
+// queue operations of the admin api, on the queues the receiver writes and the workers read:
+// {shop}:{topic} queues of requests scored by triggered-at millis
+#[derive(Debug, Clone)]
+pub struct QueueAdmin<Store: QueueStore = AnyQueueStore> {
+    store: Store,
+}
+
+impl<Store: QueueStore> QueueAdmin<Store> {
+    pub fn new(store: Store) -> Self {
+        Self { store }
+    }
+
+    pub async fn list_queues(&self, filter: &QueueFilter) -> redis::RedisResult<Vec<QueueInfo>> {
+        let mut queues = self.store.queues().await?;
+        queues.sort();
+
+        let mut infos = vec![];
+        for queue in queues {
+            let app = config::get().app_for_queue(&queue);
+            let (shop, topic) = app
+                .and_then(|app| app.parse_queue_key(&queue))
+                .map_or((None, None), |(shop, topic)| (Some(shop), Some(topic)));
+            if !filter.matches(app.map(|app| app.name.as_str()), shop, topic) {
+                continue;
+            }
+            let stats = self.store.stats(&queue).await?;
+            let triggered_at = |score: Option<f64>| {
+                score.and_then(|s| chrono::DateTime::from_timestamp_millis(s as i64))
+            };
+            infos.push(QueueInfo {
+                app: app.map(|app| app.name.clone()),
+                shop: shop.map(str::to_string),
+                topic: topic.map(str::to_string),
+                depth: stats.depth as u64,
+                oldest: triggered_at(stats.oldest_score),
+                newest: triggered_at(stats.newest_score),
+                queue,
+            });
+        }
+        Ok(infos)
+    }
+
+    // a page of a queue in triggered-at order, decoded the same way the workers do
+    pub async fn page(&self, query: &EventQuery) -> redis::RedisResult<EventPage> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let range = score_range(&TimeRange {
+            from: query.from,
+            to: query.to,
+        });
+        let requests = self
+            .store
+            .range(&query.queue, range, query.offset, limit)
+            .await?;
+
+        let next_offset = (requests.len() as u64 == limit).then_some(query.offset + limit);
+        let events = requests
+            .into_iter()
+            .map(|queued| decode_event(&queued.request, queued.score))
+            .collect();
+        Ok(EventPage {
+            queue: query.queue.clone(),
+            events,
+            next_offset,
+        })
+    }
+
+    pub async fn purge(&self, request: &PurgeRequest) -> redis::RedisResult<PurgeResult> {
+        let range = score_range(&request.range);
+        let (matched, removed) = if request.dry_run {
+            (self.store.count(&request.queue, range).await?, 0)
+        } else {
+            let removed = self.store.remove(&request.queue, range).await?;
+            tracing::info!("purged {removed} events from {:?}", request.queue);
+            (removed, removed)
+        };
+        Ok(PurgeResult {
+            queue: request.queue.clone(),
+            matched,
+            removed,
+            dry_run: request.dry_run,
+        })
+    }
+
+    pub async fn move_queue(&self, request: &MoveRequest) -> redis::RedisResult<MoveResult> {
+        let (moved, existing) = if request.dry_run {
+            let all = ScoreRange::default();
+            (
+                self.store.count(&request.from, all).await?,
+                self.store.count(&request.to, all).await?,
+            )
+        } else {
+            let (moved, existing) = self.store.merge(&request.from, &request.to).await?;
+            tracing::info!(
+                "moved {moved} events from {:?} to {:?} ({existing} already there)",
+                request.from,
+                request.to
+            );
+            (moved, existing)
+        };
+        Ok(MoveResult {
+            from: request.from.clone(),
+            to: request.to.clone(),
+            moved,
+            existing,
+            dry_run: request.dry_run,
+        })
+    }
+
+    // NDJSON lines of up to TRANSFER_BATCH_SIZE events starting at `offset`, and the offset of
+    // the next chunk if there may be one
+    pub async fn export_chunk(
+        &self,
+        query: &ExportQuery,
+        offset: usize,
+    ) -> redis::RedisResult<(String, Option<usize>)> {
+        let requests = self
+            .store
+            .range(
+                &query.queue,
+                score_range(&query.range),
+                offset as u64,
+                TRANSFER_BATCH_SIZE as u64,
+            )
+            .await?;
+        let next = (requests.len() == TRANSFER_BATCH_SIZE).then_some(offset + TRANSFER_BATCH_SIZE);
+        let mut lines = String::new();
+        for queued in requests {
+            let event = match ReqDownstream::from_envelope(&queued.request) {
+                Ok(request) => ExportedEvent {
+                    queue: query.queue.clone(),
+                    score: queued.score,
+                    request: Some(request),
+                    envelope: None,
+                },
+                Err(_) => ExportedEvent {
+                    queue: query.queue.clone(),
+                    score: queued.score,
+                    request: None,
+                    envelope: Some(BASE64.encode(&queued.request)),
+                },
+            };
+            lines.push_str(&serde_json::to_string(&event).expect("events are serializable"));
+            lines.push('\n');
+        }
+        Ok((lines, next))
+    }
+
+    // re-enqueues exported events, requests are re-encoded in the current envelope format
+    pub async fn import(
+        &self,
+        query: &ImportQuery,
+        ndjson: &str,
+    ) -> redis::RedisResult<ImportResult> {
+        let mut result = ImportResult {
+            dry_run: query.dry_run,
+            ..Default::default()
+        };
+        let mut batch = vec![];
+        for (i, line) in ndjson.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_exported(line) {
+                Ok((queue, score, envelope)) => batch.push(Enqueue {
+                    queue: query.queue.clone().unwrap_or(queue),
+                    score: score as i64,
+                    envelope,
+                    dedup: None,
+                }),
+                Err(error) => result.errors.push(LineError { line: i + 1, error }),
+            }
+            if batch.len() == TRANSFER_BATCH_SIZE {
+                self.flush_import(std::mem::take(&mut batch), &mut result)
+                    .await?;
+            }
+        }
+        self.flush_import(batch, &mut result).await?;
+        if !query.dry_run {
+            tracing::info!("imported {} events", result.imported);
+        }
+        Ok(result)
+    }
+
+    async fn flush_import(
+        &self,
+        batch: Vec<Enqueue>,
+        result: &mut ImportResult,
+    ) -> redis::RedisResult<()> {
+        if result.dry_run {
+            result.imported += batch.len() as u64;
+            return Ok(());
+        }
+        for added in self.store.enqueue_batch(batch).await? {
+            if added {
+                result.imported += 1;
+            } else {
+                result.existing += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // enqueues captured requests (README format) into their {shop}:{topic} queue, scored by
+    // their original triggered-at. They go through the receiver's checks but the signature's and
+    // the age's, only made when asked for
+    pub async fn ingest<S: QueueStore>(
+        &self,
+        app: &AppConfig,
+        svc: &ProductServiceImpl<S>,
+        query: &IngestQuery,
+        ndjson: &str,
+    ) -> redis::RedisResult<IngestResult> {
+        let mut result = IngestResult {
+            dry_run: query.dry_run,
+            ..Default::default()
+        };
+        // line numbers of the requests in the batch
+        let mut pending = vec![];
+        for (i, line) in ndjson.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (status, error) = match prepare_ingested(app, svc, query, line) {
+                Ok(Prepared::Enqueue(enqueue)) => {
+                    pending.push((i + 1, enqueue));
+                    if pending.len() == TRANSFER_BATCH_SIZE {
+                        self.flush_ingest(std::mem::take(&mut pending), &mut result)
+                            .await?;
+                    }
+                    continue;
+                }
+                Ok(Prepared::Dropped) => {
+                    result.dropped += 1;
+                    (IngestStatus::Dropped, None)
+                }
+                Err(error) => {
+                    result.failed += 1;
+                    (IngestStatus::Failed, Some(error))
+                }
+            };
+            result.lines.push(IngestLine {
+                line: i + 1,
+                status,
+                queue: None,
+                error,
+            });
+        }
+        self.flush_ingest(pending, &mut result).await?;
+        result.lines.sort_by_key(|line| line.line);
+        if !query.dry_run {
+            tracing::info!(
+                "ingested {} requests for app {} ({} already queued, {} dropped, {} failed)",
+                result.accepted,
+                app.name,
+                result.existing,
+                result.dropped,
+                result.failed
+            );
+        }
+        Ok(result)
+    }
+
+    async fn flush_ingest(
+        &self,
+        pending: Vec<(usize, Enqueue)>,
+        result: &mut IngestResult,
+    ) -> redis::RedisResult<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let (lines, batch): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .map(|(line, enqueue)| ((line, enqueue.queue.clone()), enqueue))
+            .unzip();
+        let added = if result.dry_run {
+            vec![true; batch.len()]
+        } else {
+            self.store.enqueue_batch(batch).await?
+        };
+        for ((line, queue), added) in lines.into_iter().zip(added) {
+            let status = if added {
+                result.accepted += 1;
+                IngestStatus::Accepted
+            } else {
+                result.existing += 1;
+                IngestStatus::Existing
+            };
+            result.lines.push(IngestLine {
+                line,
+                status,
+                queue: Some(queue),
+                error: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn score_range(range: &TimeRange) -> ScoreRange {
+    ScoreRange {
+        min: range.from.map(|t| t.timestamp_millis()),
+        max: range.to.map(|t| t.timestamp_millis()),
+    }
+}
+
+// a captured request, checked like the receiver checks webhooks
+fn prepare_ingested<S: QueueStore>(
+    app: &AppConfig,
+    svc: &ProductServiceImpl<S>,
+    query: &IngestQuery,
+    line: &str,
+) -> Result<Prepared, String> {
+    let mut request: ReqDownstream = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let endpoint_topic = endpoint_topic(&request.endpoint);
+    let path_topic = route_topic(app, &mut request, endpoint_topic.as_deref());
+    let verifier = verifier::for_app(app);
+    let meta = verifier.event_meta(&request).map_err(|e| e.to_string())?;
+    if meta.triggered_at == chrono::DateTime::<chrono::Utc>::MIN_UTC {
+        return Err("no usable triggered-at".to_string());
+    }
+    if query.expire {
+        check_age(&meta).map_err(|e| e.to_string())?;
+    }
+    if query.verify {
+        let secret = verifier
+            .verify(&app.client_secrets, &request)
+            .map_err(|e| e.to_string())?;
+        request.key_id = Some(secret.id.clone());
+    }
+    svc.prepare(app, request, path_topic, &meta)
+        .map_err(|e| e.to_string())
+}
+
+// {resource}/{topic} of a /webhook/{resource}/{topic} endpoint, as the downstream sees it
+fn endpoint_topic(endpoint: &str) -> Option<String> {
+    let path = endpoint.split('?').next()?.strip_prefix("/webhook/")?;
+    match path.split('/').collect::<Vec<_>>()[..] {
+        [resource, topic] => Some(format!("{resource}/{topic}")),
+        _ => None,
+    }
+}
+
+fn decode_event(envelope: &[u8], score: f64) -> QueuedEvent {
+    match ReqDownstream::from_queued(envelope, score) {
+        Ok((request, triggered_at)) => QueuedEvent {
+            triggered_at,
+            envelope_bytes: envelope.len(),
+            request: Some(request),
+            error: None,
+        },
+        Err(e) => QueuedEvent {
+            triggered_at: chrono::DateTime::from_timestamp_millis(score as i64).unwrap_or_default(),
+            envelope_bytes: envelope.len(),
+            request: None,
+            error: Some(format!("{e:#}")),
+        },
+    }
+}
+
+fn parse_exported(line: &str) -> Result<(String, f64, Vec<u8>), String> {
+    let event: ExportedEvent = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let envelope = match (event.request, event.envelope) {
+        (Some(request), _) => request.to_envelope().map_err(|e| format!("{e:#}"))?,
+        (None, Some(envelope)) => BASE64
+            .decode(envelope.as_bytes())
+            .map_err(|e| e.to_string())?,
+        (None, None) => return Err("either request or envelope is required".to_string()),
+    };
+    Ok((event.queue, event.score, envelope))
+}
+
+#[test]
+fn test_export_roundtrip() {
+    let request = ReqDownstream {
+        endpoint: "/webhook/products/update".to_string(),
+        method: http::Method::POST,
+        headers: http::HeaderMap::new(),
+        queries: Default::default(),
+        payload: bytes::Bytes::from_static(b"\xff\xfe"),
+        key_id: Some("default".to_string()),
+    };
+    let line = serde_json::to_string(&ExportedEvent {
+        queue: "a.myshopify.com:products/update".to_string(),
+        score: 1717400000000.0,
+        request: Some(request.clone()),
+        envelope: None,
+    })
+    .unwrap();
+    let (queue, score, envelope) = parse_exported(&line).unwrap();
+    assert_eq!(queue, "a.myshopify.com:products/update");
+    assert_eq!(score, 1717400000000.0);
+    assert_eq!(ReqDownstream::from_envelope(&envelope).unwrap(), request);
+
+    let raw = r#"{"queue":"q","score":1,"envelope":"AAE="}"#;
+    assert_eq!(parse_exported(raw).unwrap().2, vec![0, 1]);
+    assert!(parse_exported(r#"{"queue":"q","score":1}"#).is_err());
+}
+
+#[tokio::test]
+async fn test_queue_admin() {
+    use crate::services::queue_store::MemoryQueueStore;
+
+    config::init_test_env();
+    let store = MemoryQueueStore::new();
+    let admin = QueueAdmin::new(store.clone());
+    let queue = "a.myshopify.com:orders/create";
+    let other = "b.myshopify.com:orders/create";
+    let enqueue = |queue: &str, score: i64| Enqueue {
+        queue: queue.to_string(),
+        score,
+        envelope: score.to_be_bytes().to_vec(),
+        dedup: None,
+    };
+    store
+        .enqueue_batch(vec![
+            enqueue(queue, 1000),
+            enqueue(queue, 2000),
+            enqueue(queue, 3000),
+            enqueue(other, 500),
+        ])
+        .await
+        .unwrap();
+
+    let infos = admin.list_queues(&QueueFilter::default()).await.unwrap();
+    assert_eq!(
+        infos
+            .iter()
+            .map(|info| (info.queue.as_str(), info.topic.as_deref(), info.depth))
+            .collect::<Vec<_>>(),
+        vec![
+            (queue, Some("orders/create"), 3),
+            (other, Some("orders/create"), 1)
+        ]
+    );
+
+    let page = admin
+        .page(&EventQuery {
+            queue: queue.to_string(),
+            from: chrono::DateTime::from_timestamp_millis(2000),
+            to: None,
+            offset: 0,
+            limit: Some(1),
+        })
+        .await
+        .unwrap();
+    assert_eq!(page.events.len(), 1);
+    assert_eq!(page.next_offset, Some(1));
+
+    let to = chrono::DateTime::from_timestamp_millis(2000);
+    let purge = |dry_run| PurgeRequest {
+        queue: queue.to_string(),
+        range: TimeRange { from: None, to },
+        dry_run,
+    };
+    let result = admin.purge(&purge(true)).await.unwrap();
+    assert_eq!((result.matched, result.removed), (2, 0));
+    let result = admin.purge(&purge(false)).await.unwrap();
+    assert_eq!((result.matched, result.removed), (2, 2));
+
+    let result = admin
+        .move_queue(&MoveRequest {
+            from: other.to_string(),
+            to: queue.to_string(),
+            dry_run: false,
+        })
+        .await
+        .unwrap();
+    assert_eq!((result.moved, result.existing), (1, 1));
+    assert_eq!(store.queues().await.unwrap(), vec![queue.to_string()]);
+    assert_eq!(store.stats(queue).await.unwrap().depth, 2);
+}
+
+#[tokio::test]
+async fn test_queue_admin_ingest() {
+    use crate::common::consts;
+    use crate::config::{PayloadSchema, SchemaFailure};
+    use crate::services::connect::Connected;
+    use crate::services::ingest_rules::IngestRules;
+    use crate::services::load_shed::LoadShedder;
+    use crate::services::payload_schema::PayloadSchemas;
+    use crate::services::queue_store::MemoryQueueStore;
+    use std::sync::Arc;
+
+    config::init_test_env();
+    let app = config::get().app(config::DEFAULT_APP).unwrap();
+    let dir = std::env::temp_dir().join(format!("webhook-ingest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let rules = dir.join("rules.json");
+    std::fs::write(
+        &rules,
+        r#"{"rules":[{"action":"reject","topics":["customers/redact"]},{"action":"drop","topics":["carts/*"]}]}"#,
+    )
+    .unwrap();
+    let schema = dir.join("products.json");
+    std::fs::write(&schema, r#"{"required":["id"]}"#).unwrap();
+    let store = MemoryQueueStore::new();
+    let svc = ProductServiceImpl::new(
+        Connected::pending(),
+        Connected::ready(store.clone()),
+        Arc::new(LoadShedder::new()),
+        Arc::new(IngestRules::load(&rules).unwrap()),
+        Arc::new(
+            PayloadSchemas::load(&[PayloadSchema {
+                topic: "products/update".to_string(),
+                path: schema,
+                on_failure: SchemaFailure::Quarantine,
+            }])
+            .unwrap(),
+        ),
+        None,
+    );
+    let line = |topic: &str, webhook_id: &str, payload: &'static str| {
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in [
+            (consts::XSHOPIFY_SHOP_DOMAIN, "a.myshopify.com"),
+            (consts::XSHOPIFY_TOPIC, topic),
+            (consts::XSHOPIFY_TRIGGERED_AT, "2024-06-03T07:12:40.120Z"),
+            (consts::XSHOPIFY_WEBHOOK_ID, webhook_id),
+        ] {
+            headers.insert(name, value.parse().unwrap());
+        }
+        let request = ReqDownstream {
+            endpoint: format!("/webhook/{topic}"),
+            method: http::Method::POST,
+            headers,
+            queries: Default::default(),
+            payload: bytes::Bytes::from_static(payload.as_bytes()),
+            key_id: None,
+        };
+        serde_json::to_string(&request).unwrap()
+    };
+    let ndjson = [
+        line("orders/create", "1", r#"{"id":1}"#),
+        line("customers/redact", "2", r#"{"id":2}"#),
+        line("products/update", "3", r#"{"title":"no id"}"#),
+        line("carts/update", "4", r#"{"id":4}"#),
+        line("orders/create", "1", r#"{"id":1}"#),
+    ]
+    .join("\n");
+
+    let admin = QueueAdmin::new(store.clone());
+    let query = IngestQuery {
+        app: None,
+        verify: false,
+        expire: false,
+        dry_run: false,
+    };
+    let result = admin.ingest(app, &svc, &query, &ndjson).await.unwrap();
+    assert_eq!(
+        result
+            .lines
+            .iter()
+            .map(|line| (line.status, line.queue.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                IngestStatus::Accepted,
+                Some(app.queue_key("a.myshopify.com", "orders/create"))
+            ),
+            (IngestStatus::Failed, None),
+            (
+                IngestStatus::Accepted,
+                Some(app.quarantine_key("a.myshopify.com", "products/update"))
+            ),
+            (IngestStatus::Dropped, None),
+            (
+                IngestStatus::Existing,
+                Some(app.queue_key("a.myshopify.com", "orders/create"))
+            ),
+        ]
+    );
+    assert_eq!(
+        result.lines[1].error.as_deref(),
+        Some("refused by an ingest rule")
+    );
+    assert_eq!(store.queues().await.unwrap().len(), 2);
+
+    // the receiver's age check, only when asked for
+    let query = IngestQuery {
+        expire: true,
+        ..query
+    };
+    let result = admin
+        .ingest(app, &svc, &query, &line("orders/paid", "5", "{}"))
+        .await
+        .unwrap();
+    assert_eq!(result.failed, 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,279 @@
+use redis::RedisResult;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Claimed, Enqueue, QueueStats, QueueStore, ScoreRange};
+
+// a sorted set, like the redis queues
+#[derive(Debug, Default)]
+struct Queue {
+    ordered: BTreeSet<(i64, Vec<u8>)>,
+    scores: HashMap<Vec<u8>, i64>,
+}
+
+impl Queue {
+    // false when the request was already there, it's only moved to the new score
+    fn add(&mut self, score: i64, request: Vec<u8>) -> bool {
+        let previous = self.scores.insert(request.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(previous, request.clone()));
+        }
+        self.ordered.insert((score, request));
+        previous.is_none()
+    }
+
+    fn within(&self, range: ScoreRange) -> impl Iterator<Item = &(i64, Vec<u8>)> {
+        self.ordered
+            .iter()
+            .filter(move |(score, _)| range.contains(*score as f64))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    queues: HashMap<String, Queue>,
+    // webhook id key => when it's forgotten
+    seen: HashMap<String, Instant>,
+    // (stats key, topic) => duplicates
+    duplicates: HashMap<(String, String), u64>,
+}
+
+// process local queues for tests, nothing survives the process
+#[derive(Debug, Clone, Default)]
+pub struct MemoryQueueStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MemoryQueueStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn duplicates(&self, stats_key: &str, topic: &str) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .duplicates
+            .get(&(stats_key.to_string(), topic.to_string()))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl QueueStore for MemoryQueueStore {
+    async fn enqueue(&self, enqueue: Enqueue) -> RedisResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(dedup) = &enqueue.dedup {
+            let now = Instant::now();
+            if inner
+                .seen
+                .get(&dedup.id_key)
+                .is_some_and(|expiry| *expiry > now)
+            {
+                *inner
+                    .duplicates
+                    .entry((dedup.stats_key.clone(), dedup.topic.clone()))
+                    .or_default() += 1;
+                return Ok(false);
+            }
+            inner
+                .seen
+                .insert(dedup.id_key.clone(), now + Duration::from_secs(dedup.ttl));
+        }
+        let queue = inner.queues.entry(enqueue.queue).or_default();
+        Ok(queue.add(enqueue.score, enqueue.envelope))
+    }
+
+    async fn queues(&self) -> RedisResult<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.queues.keys().cloned().collect())
+    }
+
+    async fn claim(&self, queue: &str, offset: u64, count: u64) -> RedisResult<Vec<Claimed>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.queues.get(queue).map_or(vec![], |queue| {
+            queue
+                .ordered
+                .iter()
+                .skip(offset as usize)
+                .take(count as usize)
+                .map(|(score, request)| Claimed {
+                    request: request.clone(),
+                    score: *score as f64,
+                    id: None,
+                })
+                .collect()
+        }))
+    }
+
+    async fn ack(&self, queue: &str, claimed: &Claimed) -> RedisResult<()> {
+        let request = claimed.request.as_slice();
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entries) = inner.queues.get_mut(queue) else {
+            return Ok(());
+        };
+        if let Some(score) = entries.scores.remove(request) {
+            entries.ordered.remove(&(score, request.to_vec()));
+        }
+        // emptied queues are gone, like empty ZSETs
+        if entries.ordered.is_empty() {
+            inner.queues.remove(queue);
+        }
+        Ok(())
+    }
+
+    async fn stats(&self, queue: &str) -> RedisResult<QueueStats> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .queues
+            .get(queue)
+            .map_or_else(QueueStats::default, |queue| QueueStats {
+                depth: queue.ordered.len() as i64,
+                oldest_score: queue.ordered.first().map(|(score, _)| *score as f64),
+                newest_score: queue.ordered.last().map(|(score, _)| *score as f64),
+            }))
+    }
+
+    async fn range(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+        offset: u64,
+        count: u64,
+    ) -> RedisResult<Vec<Claimed>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.queues.get(queue).map_or(vec![], |queue| {
+            queue
+                .within(range)
+                .skip(offset as usize)
+                .take(count as usize)
+                .map(|(score, request)| Claimed {
+                    request: request.clone(),
+                    score: *score as f64,
+                    id: None,
+                })
+                .collect()
+        }))
+    }
+
+    async fn count(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .queues
+            .get(queue)
+            .map_or(0, |queue| queue.within(range).count() as u64))
+    }
+
+    async fn remove(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entries) = inner.queues.get_mut(queue) else {
+            return Ok(0);
+        };
+        let removed: Vec<_> = entries.within(range).cloned().collect();
+        for (score, request) in &removed {
+            entries.ordered.remove(&(*score, request.clone()));
+            entries.scores.remove(request);
+        }
+        if entries.ordered.is_empty() {
+            inner.queues.remove(queue);
+        }
+        Ok(removed.len() as u64)
+    }
+
+    async fn merge(&self, from: &str, to: &str) -> RedisResult<(u64, u64)> {
+        let mut inner = self.inner.lock().unwrap();
+        let existing = inner
+            .queues
+            .get(to)
+            .map_or(0, |queue| queue.ordered.len() as u64);
+        let Some(source) = inner.queues.remove(from) else {
+            return Ok((0, existing));
+        };
+        let moved = source.ordered.len() as u64;
+        let destination = inner.queues.entry(to.to_string()).or_default();
+        for (score, request) in source.ordered {
+            // like ZUNIONSTORE ... AGGREGATE MIN
+            let score = destination
+                .scores
+                .get(&request)
+                .map_or(score, |current| score.min(*current));
+            destination.add(score, request);
+        }
+        Ok((moved, existing))
+    }
+}
+
+#[test]
+fn test_memory_queue_store() {
+    use super::Dedup;
+
+    let store = MemoryQueueStore::new();
+    let enqueue = |envelope: &[u8], score, id: Option<&str>| Enqueue {
+        queue: "default:a.myshopify.com:orders/create".to_string(),
+        score,
+        envelope: envelope.to_vec(),
+        dedup: id.map(|id| Dedup {
+            id_key: format!("webhook-id:{id}"),
+            ttl: 60,
+            stats_key: "stats:duplicate-webhooks".to_string(),
+            topic: "orders/create".to_string(),
+        }),
+    };
+    let queue = "default:a.myshopify.com:orders/create";
+    futures::executor::block_on(async {
+        assert!(store.enqueue(enqueue(b"b", 2, Some("1"))).await.unwrap());
+        assert!(store.enqueue(enqueue(b"a", 1, None)).await.unwrap());
+        assert!(!store.enqueue(enqueue(b"c", 3, Some("1"))).await.unwrap());
+        assert_eq!(
+            store.duplicates("stats:duplicate-webhooks", "orders/create"),
+            1
+        );
+        assert_eq!(store.queues().await.unwrap(), vec![queue.to_string()]);
+        assert_eq!(
+            store.stats(queue).await.unwrap(),
+            QueueStats {
+                depth: 2,
+                oldest_score: Some(1.0),
+                newest_score: Some(2.0),
+            }
+        );
+        let claimed = store.claim(queue, 0, 10).await.unwrap();
+        assert_eq!(
+            claimed
+                .iter()
+                .map(|c| (c.request.as_slice(), c.score))
+                .collect::<Vec<_>>(),
+            vec![(b"a".as_slice(), 1.0), (b"b".as_slice(), 2.0)]
+        );
+        assert_eq!(store.claim(queue, 1, 10).await.unwrap().len(), 1);
+        let up_to_1 = ScoreRange {
+            min: None,
+            max: Some(1),
+        };
+        assert_eq!(store.count(queue, up_to_1).await.unwrap(), 1);
+        assert_eq!(
+            store.range(queue, up_to_1, 0, 10).await.unwrap(),
+            claimed[..1]
+        );
+        // the same request again isn't enqueued twice
+        assert!(!store.enqueue(enqueue(b"a", 1, None)).await.unwrap());
+        for claimed in &claimed {
+            store.ack(queue, claimed).await.unwrap();
+        }
+        assert!(store.queues().await.unwrap().is_empty());
+        assert_eq!(store.stats(queue).await.unwrap(), QueueStats::default());
+
+        // merging keeps the lowest score of requests in both queues
+        let other = "default:b.myshopify.com:orders/create";
+        store.enqueue(enqueue(b"a", 5, None)).await.unwrap();
+        store.enqueue(enqueue(b"b", 6, None)).await.unwrap();
+        let mut moved = enqueue(b"a", 4, None);
+        moved.queue = other.to_string();
+        store.enqueue(moved).await.unwrap();
+        assert_eq!(store.merge(other, queue).await.unwrap(), (1, 2));
+        assert_eq!(store.queues().await.unwrap(), vec![queue.to_string()]);
+        assert_eq!(store.stats(queue).await.unwrap().oldest_score, Some(4.0));
+        assert_eq!(store.remove(queue, ScoreRange::default()).await.unwrap(), 2);
+        assert!(store.queues().await.unwrap().is_empty());
+    });
+}
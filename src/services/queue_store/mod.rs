@@ -0,0 +1,449 @@
+use redis::aio::ConnectionManager;
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::common::metrics;
+use crate::config::{self, AppConfig, QueueBackend};
+use crate::services::pause::PauseFlags;
+use crate::services::write_coalescer::WriteCoalescer;
+
+mod memory;
+pub(crate) mod redis_store;
+mod stream_store;
+
+pub use memory::MemoryQueueStore;
+pub use redis_store::RedisQueueStore;
+pub use stream_store::StreamQueueStore;
+
+// one webhook's write to its queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Enqueue {
+    pub queue: String,
+    // when the event was triggered, in millis
+    pub score: i64,
+    pub envelope: Vec<u8>,
+    pub dedup: Option<Dedup>,
+}
+
+// enqueues only if the webhook id wasn't seen within the ttl
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dedup {
+    pub id_key: String,
+    pub ttl: u64,
+    // per topic duplicate counters
+    pub stats_key: String,
+    pub topic: String,
+}
+
+// a request taken from a queue, it stays there until acked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claimed {
+    pub request: Vec<u8>,
+    pub score: f64,
+    // of the stream entry, sorted sets ack by the request itself
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueStats {
+    pub depth: i64,
+    pub oldest_score: Option<f64>,
+    pub newest_score: Option<f64>,
+}
+
+// scores (triggered-at millis) from `min` to `max`, both included, unbounded when none
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ScoreRange {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl ScoreRange {
+    pub fn contains(&self, score: f64) -> bool {
+        !self.min.is_some_and(|min| score < min as f64)
+            && !self.max.is_some_and(|max| score > max as f64)
+    }
+}
+
+// where the receiver enqueues requests, the workers take them from and the admin api looks into.
+// Queues are ordered by score, a request stays queued until it's acked
+pub trait QueueStore: Clone + Send + Sync + 'static {
+    // false when the webhook is a duplicate, or the same request is already queued (streams: was
+    // enqueued within the last 3 days), and was not enqueued again
+    fn enqueue(&self, enqueue: Enqueue) -> impl Future<Output = RedisResult<bool>> + Send;
+
+    // the same for many requests at once, e.g. imports
+    fn enqueue_batch(
+        &self,
+        batch: Vec<Enqueue>,
+    ) -> impl Future<Output = RedisResult<Vec<bool>>> + Send {
+        async move {
+            let mut enqueued = Vec::with_capacity(batch.len());
+            for enqueue in batch {
+                enqueued.push(self.enqueue(enqueue).await?);
+            }
+            Ok(enqueued)
+        }
+    }
+
+    // every non-empty queue, quarantined ones included
+    fn queues(&self) -> impl Future<Output = RedisResult<Vec<String>>> + Send;
+
+    // up to `count` requests from the `offset`th on, oldest first
+    fn claim(
+        &self,
+        queue: &str,
+        offset: u64,
+        count: u64,
+    ) -> impl Future<Output = RedisResult<Vec<Claimed>>> + Send;
+
+    fn ack(&self, queue: &str, claimed: &Claimed) -> impl Future<Output = RedisResult<()>> + Send;
+
+    // done with the queue for now, stores handing queues to one worker at a time let it go
+    fn release(&self, _queue: &str) -> impl Future<Output = RedisResult<()>> + Send {
+        async { Ok(()) }
+    }
+
+    // keeps the queue ours while its claimed requests are delivered, false once another worker
+    // has it. Called every hold_interval, stores without leases don't need it
+    fn hold(&self, _queue: &str) -> impl Future<Output = RedisResult<bool>> + Send {
+        async { Ok(true) }
+    }
+
+    fn hold_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn stats(&self, queue: &str) -> impl Future<Output = RedisResult<QueueStats>> + Send;
+
+    // up to `count` requests within the range, from the `offset`th on, oldest first. Unlike a
+    // claim it's only a look, nothing is handed to this consumer
+    fn range(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+        offset: u64,
+        count: u64,
+    ) -> impl Future<Output = RedisResult<Vec<Claimed>>> + Send;
+
+    fn count(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+    ) -> impl Future<Output = RedisResult<u64>> + Send;
+
+    // how many requests within the range were removed
+    fn remove(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+    ) -> impl Future<Output = RedisResult<u64>> + Send;
+
+    // moves every request of `from` into `to`, which keeps its score order, then deletes `from`.
+    // (moved, already in `to`)
+    fn merge(&self, from: &str, to: &str) -> impl Future<Output = RedisResult<(u64, u64)>> + Send;
+}
+
+// runs `work`, the delivery of a queue's claimed requests, holding the queue meanwhile. None when
+// the queue was lost: `work` is dropped right away, what it didn't ack is left to the new holder
+pub async fn while_held<Store: QueueStore, T>(
+    store: &Store,
+    queue: &str,
+    work: impl Future<Output = T>,
+) -> Option<T> {
+    let Some(period) = store.hold_interval() else {
+        return Some(work.await);
+    };
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            res = &mut work => return Some(res),
+            _ = ticker.tick() => match store.hold(queue).await {
+                Ok(true) => {}
+                Ok(false) => return None,
+                // the acks fail as well if redis is gone
+                Err(e) => tracing::warn!("unable to renew the hold of {queue:?}: {e:?}"),
+            },
+        }
+    }
+}
+
+// the queues a worker round delivers, quarantine and paused ones left out. The stats of queues
+// gone since the last round are dropped
+pub fn deliverable(queues: Vec<String>, pause_flags: &PauseFlags) -> Vec<String> {
+    metrics::retain_queue_stats(&queues);
+    let cnf = config::get();
+    queues
+        .into_iter()
+        .filter(|queue| {
+            // quarantined events are only ever moved out by hand
+            if cnf.is_quarantine_queue(queue) {
+                return false;
+            }
+            if pause_flags.is_queue_paused(queue) {
+                tracing::debug!("{queue:?} is paused, skipped");
+                return false;
+            }
+            true
+        })
+        .collect()
+}
+
+// delivers a queue's requests, oldest first, a batch at a time while the worker holds it, then
+// lets it go. `deliver` acks what it handled, its first error stops the queue. `paused` is asked
+// before every batch but the first: flags set while the queue is drained apply from its next batch
+pub async fn drain<Store, Paused, PausedFut, Deliver, DeliverFut>(
+    store: &Store,
+    queue: &str,
+    batch_size: u64,
+    paused: Paused,
+    deliver: Deliver,
+) -> anyhow::Result<()>
+where
+    Store: QueueStore,
+    Paused: Fn() -> PausedFut,
+    PausedFut: Future<Output = RedisResult<bool>>,
+    Deliver: Fn(&'static AppConfig, Claimed) -> DeliverFut,
+    DeliverFut: Future<Output = anyhow::Result<()>>,
+{
+    let Some(app) = config::get().app_for_queue(queue) else {
+        tracing::warn!("no app configured for queue {queue:?}, skipped");
+        return Ok(());
+    };
+    record_stats(store, app, queue).await;
+    let mut range_start = 0;
+    loop {
+        if range_start > 0 && paused().await? {
+            tracing::info!("{queue:?} paused, stopped handling it");
+            break;
+        }
+        let queued_requests = store
+            .claim(queue, range_start, batch_size)
+            .await
+            .inspect_err(|e| tracing::error!("{e:?}"))?;
+        tracing::debug!("queued requests: {queued_requests:?}");
+        if queued_requests.is_empty() {
+            tracing::debug!("requests queue empty");
+            break;
+        }
+        range_start += batch_size;
+
+        let delivered = while_held(store, queue, async {
+            for claimed in queued_requests {
+                deliver(app, claimed).await?;
+            }
+            anyhow::Ok(())
+        })
+        .await;
+        match delivered {
+            Some(delivered) => delivered?,
+            None => {
+                tracing::warn!("{queue:?} taken over by another worker, stopped handling it");
+                break;
+            }
+        }
+    }
+    if let Err(e) = store.release(queue).await {
+        tracing::warn!("unable to release {queue:?}: {e:?}");
+    }
+    // emptied queues are gone from the next scan, so report them here
+    record_stats(store, app, queue).await;
+    tracing::info!("finished handling requests in {queue:?}");
+    Ok(())
+}
+
+async fn record_stats(store: &impl QueueStore, app: &AppConfig, queue: &str) {
+    let topic = app
+        .parse_queue_key(queue)
+        .map_or(metrics::UNKNOWN, |(_, topic)| topic);
+    match store.stats(queue).await {
+        Ok(stats) => {
+            metrics::record_queue_stats(&app.name, topic, queue, stats.depth, stats.oldest_score)
+        }
+        Err(e) => tracing::warn!("unable to sample stats of {queue:?}: {e:?}"),
+    }
+}
+
+// the store of QUEUE_BACKEND
+#[derive(Debug, Clone)]
+pub enum AnyQueueStore {
+    Zset(RedisQueueStore),
+    Stream(StreamQueueStore),
+}
+
+impl AnyQueueStore {
+    // the coalescer only batches sorted set writes
+    pub fn from_config(
+        redis_conn: ConnectionManager,
+        write_coalescer: Option<WriteCoalescer>,
+    ) -> Self {
+        let cnf = config::get();
+        match cnf.queue_backend {
+            QueueBackend::Zset => Self::Zset(RedisQueueStore::new(redis_conn, write_coalescer)),
+            QueueBackend::Stream => {
+                if write_coalescer.is_some() {
+                    tracing::warn!("WRITE_BATCH_SIZE is ignored by the stream backend");
+                }
+                Self::Stream(StreamQueueStore::new(
+                    redis_conn,
+                    &cnf.stream_group,
+                    &cnf.consumer_name(),
+                    cnf.stream_lease_ms,
+                ))
+            }
+        }
+    }
+}
+
+impl QueueStore for AnyQueueStore {
+    async fn enqueue(&self, enqueue: Enqueue) -> RedisResult<bool> {
+        match self {
+            Self::Zset(store) => store.enqueue(enqueue).await,
+            Self::Stream(store) => store.enqueue(enqueue).await,
+        }
+    }
+
+    async fn queues(&self) -> RedisResult<Vec<String>> {
+        match self {
+            Self::Zset(store) => store.queues().await,
+            Self::Stream(store) => store.queues().await,
+        }
+    }
+
+    async fn claim(&self, queue: &str, offset: u64, count: u64) -> RedisResult<Vec<Claimed>> {
+        match self {
+            Self::Zset(store) => store.claim(queue, offset, count).await,
+            Self::Stream(store) => store.claim(queue, offset, count).await,
+        }
+    }
+
+    async fn ack(&self, queue: &str, claimed: &Claimed) -> RedisResult<()> {
+        match self {
+            Self::Zset(store) => store.ack(queue, claimed).await,
+            Self::Stream(store) => store.ack(queue, claimed).await,
+        }
+    }
+
+    async fn release(&self, queue: &str) -> RedisResult<()> {
+        match self {
+            Self::Zset(store) => store.release(queue).await,
+            Self::Stream(store) => store.release(queue).await,
+        }
+    }
+
+    async fn hold(&self, queue: &str) -> RedisResult<bool> {
+        match self {
+            Self::Zset(store) => store.hold(queue).await,
+            Self::Stream(store) => store.hold(queue).await,
+        }
+    }
+
+    fn hold_interval(&self) -> Option<Duration> {
+        match self {
+            Self::Zset(store) => store.hold_interval(),
+            Self::Stream(store) => store.hold_interval(),
+        }
+    }
+
+    async fn stats(&self, queue: &str) -> RedisResult<QueueStats> {
+        match self {
+            Self::Zset(store) => store.stats(queue).await,
+            Self::Stream(store) => store.stats(queue).await,
+        }
+    }
+
+    async fn enqueue_batch(&self, batch: Vec<Enqueue>) -> RedisResult<Vec<bool>> {
+        match self {
+            Self::Zset(store) => store.enqueue_batch(batch).await,
+            Self::Stream(store) => store.enqueue_batch(batch).await,
+        }
+    }
+
+    async fn range(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+        offset: u64,
+        count: u64,
+    ) -> RedisResult<Vec<Claimed>> {
+        match self {
+            Self::Zset(store) => store.range(queue, range, offset, count).await,
+            Self::Stream(store) => store.range(queue, range, offset, count).await,
+        }
+    }
+
+    async fn count(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        match self {
+            Self::Zset(store) => store.count(queue, range).await,
+            Self::Stream(store) => store.count(queue, range).await,
+        }
+    }
+
+    async fn remove(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        match self {
+            Self::Zset(store) => store.remove(queue, range).await,
+            Self::Stream(store) => store.remove(queue, range).await,
+        }
+    }
+
+    async fn merge(&self, from: &str, to: &str) -> RedisResult<(u64, u64)> {
+        match self {
+            Self::Zset(store) => store.merge(from, to).await,
+            Self::Stream(store) => store.merge(from, to).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_drain() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    config::init_test_env();
+    let app = config::get().app(config::DEFAULT_APP).unwrap();
+    let queue = app.queue_key("a.myshopify.com", "orders/create");
+    let store = MemoryQueueStore::new();
+    for score in 1..=3 {
+        store
+            .enqueue(Enqueue {
+                queue: queue.clone(),
+                score,
+                envelope: score.to_be_bytes().to_vec(),
+                dedup: None,
+            })
+            .await
+            .unwrap();
+    }
+    let quarantine = app.quarantine_key("a.myshopify.com", "orders/create");
+    assert_eq!(
+        deliverable(vec![queue.clone(), quarantine], &PauseFlags::default()),
+        std::slice::from_ref(&queue)
+    );
+
+    let delivered = AtomicUsize::new(0);
+    let deliver = |_: &'static AppConfig, claimed: Claimed| {
+        delivered.fetch_add(1, Ordering::Relaxed);
+        let store = store.clone();
+        let queue = queue.clone();
+        async move { Ok(store.ack(&queue, &claimed).await?) }
+    };
+    // paused after the first batch
+    drain(&store, &queue, 2, || async { Ok(true) }, deliver)
+        .await
+        .unwrap();
+    assert_eq!(delivered.load(Ordering::Relaxed), 2);
+    assert_eq!(store.stats(&queue).await.unwrap().depth, 1);
+
+    // a failed delivery stops the queue, the request stays queued
+    let failed = drain(
+        &store,
+        &queue,
+        2,
+        || async { Ok(false) },
+        |_, _| async { Err(anyhow::anyhow!("downstream down")) },
+    )
+    .await;
+    assert!(failed.is_err());
+    assert_eq!(store.stats(&queue).await.unwrap().depth, 1);
+}
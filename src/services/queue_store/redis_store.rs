@@ -0,0 +1,259 @@
+use once_cell::sync::Lazy;
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::{AsyncCommands, RedisResult};
+use std::collections::HashSet;
+
+use super::{Claimed, Enqueue, QueueStats, QueueStore, ScoreRange};
+use crate::services::write_coalescer::WriteCoalescer;
+
+// ZRANGEBYSCORE ... WITHSCORES replies
+type ScoredMembers = Vec<(Vec<u8>, f64)>;
+
+// queues are ZSETs of envelopes scored by when their event was triggered
+#[derive(Clone)]
+pub struct RedisQueueStore {
+    redis_conn: ConnectionManager,
+    // batches the enqueues when WRITE_BATCH_SIZE is set
+    write_coalescer: Option<WriteCoalescer>,
+}
+
+impl RedisQueueStore {
+    pub fn new(redis_conn: ConnectionManager, write_coalescer: Option<WriteCoalescer>) -> Self {
+        Self {
+            redis_conn,
+            write_coalescer,
+        }
+    }
+}
+
+impl std::fmt::Debug for RedisQueueStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisQueueStore")
+            .field("redis_conn: ", &self.redis_conn.get_db())
+            .field("coalesced", &self.write_coalescer.is_some())
+            .finish()
+    }
+}
+
+impl QueueStore for RedisQueueStore {
+    async fn enqueue(&self, enqueue: Enqueue) -> RedisResult<bool> {
+        match &self.write_coalescer {
+            Some(coalescer) => coalescer.enqueue(enqueue).await,
+            None => enqueue.run(&mut self.redis_conn.clone()).await,
+        }
+    }
+
+    async fn queues(&self) -> RedisResult<Vec<String>> {
+        let mut redis_conn = self.redis_conn.clone();
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(0).arg("TYPE").arg("ZSET");
+        let mut iter: redis::AsyncIter<String> = cmd.iter_async(&mut redis_conn).await?;
+        // SCAN may return a key more than once
+        let mut seen = HashSet::new();
+        let mut queues = vec![];
+        while let Some(queue) = iter.next_item().await {
+            if seen.insert(queue.clone()) {
+                queues.push(queue);
+            }
+        }
+        Ok(queues)
+    }
+
+    async fn claim(&self, queue: &str, offset: u64, count: u64) -> RedisResult<Vec<Claimed>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let requests: Vec<(Vec<u8>, f64)> = self
+            .redis_conn
+            .clone()
+            .zrange_withscores(queue, offset as isize, (offset + count - 1) as isize)
+            .await?;
+        Ok(requests
+            .into_iter()
+            .map(|(request, score)| Claimed {
+                request,
+                score,
+                id: None,
+            })
+            .collect())
+    }
+
+    async fn ack(&self, queue: &str, claimed: &Claimed) -> RedisResult<()> {
+        let removed: i64 = self
+            .redis_conn
+            .clone()
+            .zrem(queue, &claimed.request)
+            .await?;
+        tracing::debug!("removed {removed} request from {queue}");
+        Ok(())
+    }
+
+    async fn stats(&self, queue: &str) -> RedisResult<QueueStats> {
+        let (depth, oldest, newest): (i64, ScoredMembers, ScoredMembers) = redis::pipe()
+            .zcard(queue)
+            .zrange_withscores(queue, 0, 0)
+            .zrange_withscores(queue, -1, -1)
+            .query_async(&mut self.redis_conn.clone())
+            .await?;
+        Ok(QueueStats {
+            depth,
+            oldest_score: oldest.first().map(|(_, score)| *score),
+            newest_score: newest.first().map(|(_, score)| *score),
+        })
+    }
+
+    // imports are pipelined, not coalesced with the receiver's writes
+    async fn enqueue_batch(&self, batch: Vec<Enqueue>) -> RedisResult<Vec<bool>> {
+        if batch.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut redis_conn = self.redis_conn.clone();
+        if batch.iter().any(|enqueue| enqueue.dedup.is_some()) {
+            ENQUEUE_ONCE_SCRIPT
+                .prepare_invoke()
+                .load_async(&mut redis_conn)
+                .await?;
+        }
+        let mut pipe = redis::pipe();
+        for enqueue in &batch {
+            enqueue.add_to(&mut pipe);
+        }
+        let replies: Vec<redis::Value> = pipe.query_async(&mut redis_conn).await?;
+        Ok(batch
+            .iter()
+            .zip(&replies)
+            .map(|(enqueue, reply)| enqueue.enqueued(reply))
+            .collect())
+    }
+
+    async fn range(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+        offset: u64,
+        count: u64,
+    ) -> RedisResult<Vec<Claimed>> {
+        let (min, max) = bounds(range);
+        let members: ScoredMembers = self
+            .redis_conn
+            .clone()
+            .zrangebyscore_limit_withscores(queue, min, max, offset as isize, count as isize)
+            .await?;
+        Ok(members
+            .into_iter()
+            .map(|(request, score)| Claimed {
+                request,
+                score,
+                id: None,
+            })
+            .collect())
+    }
+
+    async fn count(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        let (min, max) = bounds(range);
+        self.redis_conn.clone().zcount(queue, min, max).await
+    }
+
+    async fn remove(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        let (min, max) = bounds(range);
+        self.redis_conn.clone().zrembyscore(queue, min, max).await
+    }
+
+    async fn merge(&self, from: &str, to: &str) -> RedisResult<(u64, u64)> {
+        MOVE_QUEUE_SCRIPT
+            .key(from)
+            .key(to)
+            .invoke_async(&mut self.redis_conn.clone())
+            .await
+    }
+}
+
+// ZRANGEBYSCORE bounds
+fn bounds(range: ScoreRange) -> (String, String) {
+    let bound = |score: Option<i64>, unbounded: &str| {
+        score.map_or(unbounded.to_string(), |score| score.to_string())
+    };
+    (bound(range.min, "-inf"), bound(range.max, "+inf"))
+}
+
+impl Enqueue {
+    // false when the webhook is a duplicate or already queued
+    pub(crate) async fn run(&self, redis_conn: &mut ConnectionManager) -> RedisResult<bool> {
+        match &self.dedup {
+            Some(dedup) => {
+                ENQUEUE_ONCE_SCRIPT
+                    .key(&dedup.id_key)
+                    .key(&self.queue)
+                    .key(&dedup.stats_key)
+                    .arg(dedup.ttl)
+                    .arg(self.score)
+                    .arg(&self.envelope)
+                    .arg(&dedup.topic)
+                    .invoke_async(redis_conn)
+                    .await
+            }
+            None => {
+                let added: u64 = redis_conn
+                    .zadd(&self.queue, &self.envelope, self.score)
+                    .await?;
+                Ok(added > 0)
+            }
+        }
+    }
+
+    // pipelines can't invoke scripts, EVALSHA it is. The script is loaded by the coalescer
+    pub(crate) fn add_to(&self, pipe: &mut redis::Pipeline) {
+        match &self.dedup {
+            Some(dedup) => pipe
+                .cmd("EVALSHA")
+                .arg(ENQUEUE_ONCE_SCRIPT.get_hash())
+                .arg(3)
+                .arg(&dedup.id_key)
+                .arg(&self.queue)
+                .arg(&dedup.stats_key)
+                .arg(dedup.ttl)
+                .arg(self.score)
+                .arg(&self.envelope)
+                .arg(&dedup.topic),
+            None => pipe.zadd(&self.queue, &self.envelope, self.score),
+        };
+    }
+
+    // the ZADD's or the script's reply
+    pub(crate) fn enqueued(&self, reply: &redis::Value) -> bool {
+        matches!(reply, redis::Value::Int(1))
+    }
+}
+
+// remembers the webhook id and enqueues the request in one round trip, or only bumps the
+// duplicate counter (per topic) when the id has been seen within the ttl
+pub(crate) static ENQUEUE_ONCE_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if redis.call('SET', KEYS[1], 1, 'NX', 'EX', ARGV[1]) then
+            redis.call('ZADD', KEYS[2], ARGV[2], ARGV[3])
+            return 1
+        end
+        redis.call('HINCRBY', KEYS[3], ARGV[4], 1)
+        return 0
+        ",
+    )
+});
+
+// merges the source into the destination (keeping the lowest score of events in both) then
+// drops the source, in one step. Not a guard against the workers: a batch they claimed from the
+// source before it was paused is still delivered, and its events are delivered again from the
+// destination
+static MOVE_QUEUE_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        local moved = redis.call('ZCARD', KEYS[1])
+        local existing = redis.call('ZCARD', KEYS[2])
+        if moved > 0 then
+            redis.call('ZUNIONSTORE', KEYS[2], 2, KEYS[2], KEYS[1], 'AGGREGATE', 'MIN')
+            redis.call('DEL', KEYS[1])
+        end
+        return {moved, existing}
+        ",
+    )
+});
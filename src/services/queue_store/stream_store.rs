@@ -0,0 +1,727 @@
+use once_cell::sync::Lazy;
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::streams::{
+    StreamClaimOptions, StreamPendingCountReply, StreamRangeReply, StreamReadOptions,
+    StreamReadReply,
+};
+use redis::{AsyncCommands, RedisResult};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Claimed, Enqueue, QueueStats, QueueStore, ScoreRange};
+use crate::common::{consts, metrics};
+
+// entry fields: the envelope and its event's triggered-at millis
+const ENVELOPE_FIELD: &str = "e";
+const SCORE_FIELD: &str = "s";
+// pending entries looked at per XPENDING when taking a queue over
+const RECOVER_BATCH: usize = 1000;
+// entries per XRANGE when the admin api looks through or moves a queue
+const SCAN_BATCH: usize = 500;
+// how long an envelope enqueued without a webhook id is remembered, as long as the receiver
+// accepts an event (a spool replayed again after a crash, an import retried)
+const ENTRY_TTL: u64 = 3 * 24 * 3600;
+
+// how a claim found the queue's lease
+#[derive(Debug, PartialEq)]
+enum Lease {
+    // someone else's, the queue is left alone
+    Taken,
+    Held,
+    // was free, whatever other consumers left pending is ours now
+    Acquired,
+}
+
+// queues are streams read through a consumer group, so workers on several hosts share them. A
+// queue is only read by the consumer holding its lease (stream-owner:{queue}), which keeps the
+// requests of a queue in order. Streams keep their entries in arrival order, not triggered-at
+#[derive(Clone)]
+pub struct StreamQueueStore {
+    redis_conn: ConnectionManager,
+    group: String,
+    consumer: String,
+    lease_ms: u64,
+    // streams the group is known to exist on
+    grouped: Arc<Mutex<HashSet<String>>>,
+}
+
+impl StreamQueueStore {
+    pub fn new(redis_conn: ConnectionManager, group: &str, consumer: &str, lease_ms: u64) -> Self {
+        Self {
+            redis_conn,
+            group: group.to_string(),
+            consumer: consumer.to_string(),
+            lease_ms,
+            grouped: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    async fn ensure_group(&self, queue: &str) -> RedisResult<()> {
+        if self.grouped.lock().unwrap().contains(queue) {
+            return Ok(());
+        }
+        // from the start of the stream, so nothing enqueued before the group existed is skipped
+        let res: RedisResult<()> = self
+            .redis_conn
+            .clone()
+            .xgroup_create(queue, &self.group, "0")
+            .await;
+        match res {
+            Ok(()) => {}
+            Err(e) if e.code() == Some("BUSYGROUP") => {}
+            Err(e) => return Err(e),
+        }
+        self.grouped.lock().unwrap().insert(queue.to_string());
+        Ok(())
+    }
+
+    async fn lease(&self, queue: &str) -> RedisResult<Lease> {
+        let held: i64 = LEASE_SCRIPT
+            .key(owner_key(queue))
+            .arg(&self.consumer)
+            .arg(self.lease_ms)
+            .invoke_async(&mut self.redis_conn.clone())
+            .await?;
+        Ok(match held {
+            0 => Lease::Taken,
+            2 => Lease::Acquired,
+            _ => Lease::Held,
+        })
+    }
+
+    // XCLAIMs what other consumers read but never acked, e.g. before they crashed. Their lease is
+    // gone, so they no longer work on the queue
+    async fn recover(&self, queue: &str) -> RedisResult<()> {
+        let mut redis_conn = self.redis_conn.clone();
+        let mut start = "-".to_string();
+        loop {
+            let pending: StreamPendingCountReply = redis_conn
+                .xpending_count(queue, &self.group, &start, "+", RECOVER_BATCH)
+                .await?;
+            let Some(last) = pending.ids.last() else {
+                return Ok(());
+            };
+            // the next page, our own entries would come first again otherwise
+            start = format!("({}", last.id);
+            let ids: Vec<&str> = pending
+                .ids
+                .iter()
+                .filter(|pending| pending.consumer != self.consumer)
+                .map(|pending| pending.id.as_str())
+                .collect();
+            if !ids.is_empty() {
+                let claimed: Vec<String> = redis_conn
+                    .xclaim_options(
+                        queue,
+                        &self.group,
+                        &self.consumer,
+                        0,
+                        &ids,
+                        StreamClaimOptions::default().with_justid(),
+                    )
+                    .await?;
+                tracing::info!(
+                    "took over {} pending requests of {queue:?} from other consumers",
+                    claimed.len()
+                );
+                metrics::STREAM_RECLAIMED.inc_by(claimed.len() as u64);
+            }
+            if pending.ids.len() < RECOVER_BATCH {
+                return Ok(());
+            }
+        }
+    }
+
+    // `id` 0 reads our own pending entries from the start, `>` entries no one has read yet
+    async fn read(&self, queue: &str, id: &str, count: u64) -> RedisResult<Vec<Claimed>> {
+        let mut redis_conn = self.redis_conn.clone();
+        let opts = StreamReadOptions::default()
+            .group(&self.group, &self.consumer)
+            .count(count as usize);
+        let reply: Option<StreamReadReply> =
+            redis_conn.xread_options(&[queue], &[id], &opts).await?;
+        let mut claimed = vec![];
+        for entry in reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids)
+        {
+            match (
+                entry.get::<Vec<u8>>(ENVELOPE_FIELD),
+                entry.get::<f64>(SCORE_FIELD),
+            ) {
+                (Some(request), Some(score)) => claimed.push(Claimed {
+                    request,
+                    score,
+                    id: Some(entry.id),
+                }),
+                // deleted (XDEL) while pending, or not written by the receiver
+                _ => {
+                    tracing::warn!("unreadable entry {} in {queue:?}, dropped", entry.id);
+                    redis::pipe()
+                        .atomic()
+                        .xack(queue, &self.group, &[&entry.id])
+                        .xdel(queue, &[&entry.id])
+                        .query_async::<_, ()>(&mut redis_conn)
+                        .await?;
+                }
+            }
+        }
+        Ok(claimed)
+    }
+
+    // the entries of `queue` whose score is within `range`, in arrival order, up to `limit`.
+    // Streams aren't indexed by score, the whole stream may be read
+    async fn within(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+        limit: Option<usize>,
+    ) -> RedisResult<Vec<Claimed>> {
+        let mut redis_conn = self.redis_conn.clone();
+        let mut within = vec![];
+        let mut start = "-".to_string();
+        loop {
+            let reply: StreamRangeReply = redis_conn
+                .xrange_count(queue, &start, "+", SCAN_BATCH)
+                .await?;
+            let Some(last) = reply.ids.last() else {
+                return Ok(within);
+            };
+            start = format!("({}", last.id);
+            let done = reply.ids.len() < SCAN_BATCH;
+            for entry in reply.ids {
+                let (Some(request), Some(score)) = (
+                    entry.get::<Vec<u8>>(ENVELOPE_FIELD),
+                    entry.get::<f64>(SCORE_FIELD),
+                ) else {
+                    continue;
+                };
+                if !range.contains(score) {
+                    continue;
+                }
+                within.push(Claimed {
+                    request,
+                    score,
+                    id: Some(entry.id),
+                });
+                if limit.is_some_and(|limit| within.len() >= limit) {
+                    return Ok(within);
+                }
+            }
+            if done {
+                return Ok(within);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for StreamQueueStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamQueueStore")
+            .field("redis_conn: ", &self.redis_conn.get_db())
+            .field("group", &self.group)
+            .field("consumer", &self.consumer)
+            .finish()
+    }
+}
+
+impl QueueStore for StreamQueueStore {
+    async fn enqueue(&self, enqueue: Enqueue) -> RedisResult<bool> {
+        let mut redis_conn = self.redis_conn.clone();
+        match &enqueue.dedup {
+            Some(dedup) => {
+                XADD_ONCE_SCRIPT
+                    .key(&dedup.id_key)
+                    .key(&enqueue.queue)
+                    .key(&dedup.stats_key)
+                    .arg(dedup.ttl)
+                    .arg(enqueue.score)
+                    .arg(&enqueue.envelope)
+                    .arg(&dedup.topic)
+                    .invoke_async(&mut redis_conn)
+                    .await
+            }
+            None => {
+                XADD_NEW_SCRIPT
+                    .key(entry_key(&enqueue.queue, &enqueue.envelope))
+                    .key(&enqueue.queue)
+                    .arg(ENTRY_TTL)
+                    .arg(enqueue.score)
+                    .arg(&enqueue.envelope)
+                    .invoke_async(&mut redis_conn)
+                    .await
+            }
+        }
+    }
+
+    async fn queues(&self) -> RedisResult<Vec<String>> {
+        let mut redis_conn = self.redis_conn.clone();
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(0).arg("TYPE").arg("STREAM");
+        let mut iter: redis::AsyncIter<String> = cmd.iter_async(&mut redis_conn).await?;
+        // SCAN may return a key more than once
+        let mut seen = HashSet::new();
+        let mut queues = vec![];
+        while let Some(queue) = iter.next_item().await {
+            if seen.insert(queue.clone()) {
+                queues.push(queue);
+            }
+        }
+        drop(iter);
+        if queues.is_empty() {
+            return Ok(queues);
+        }
+        // unlike empty ZSETs, drained streams stay until a release drops them
+        let mut pipe = redis::pipe();
+        for queue in &queues {
+            pipe.xlen(queue);
+        }
+        let depths: Vec<u64> = pipe.query_async(&mut redis_conn).await?;
+        Ok(queues
+            .into_iter()
+            .zip(depths)
+            .filter_map(|(queue, depth)| (depth > 0).then_some(queue))
+            .collect())
+    }
+
+    // our pending entries first (they failed or were taken over), then new ones. Empty when
+    // another consumer holds the queue
+    async fn claim(&self, queue: &str, offset: u64, count: u64) -> RedisResult<Vec<Claimed>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        self.ensure_group(queue).await?;
+        match self.lease(queue).await? {
+            Lease::Taken => {
+                tracing::debug!("{queue:?} is held by another consumer");
+                return Ok(vec![]);
+            }
+            Lease::Acquired => self.recover(queue).await?,
+            Lease::Held => {}
+        }
+        let res = async {
+            if offset == 0 {
+                let pending = self.read(queue, "0", count).await?;
+                if !pending.is_empty() {
+                    return Ok(pending);
+                }
+            }
+            self.read(queue, ">", count).await
+        }
+        .await;
+        if res.is_err() {
+            // e.g. the stream was deleted along with its group
+            self.grouped.lock().unwrap().remove(queue);
+        }
+        res
+    }
+
+    async fn ack(&self, queue: &str, claimed: &Claimed) -> RedisResult<()> {
+        let Some(id) = &claimed.id else {
+            return Ok(());
+        };
+        redis::pipe()
+            .atomic()
+            .xack(queue, &self.group, &[id])
+            .xdel(queue, &[id])
+            .query_async::<_, ()>(&mut self.redis_conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn hold(&self, queue: &str) -> RedisResult<bool> {
+        let held = self.lease(queue).await? != Lease::Taken;
+        if !held {
+            tracing::warn!("lost the lease of {queue:?}, it ran out while handling it");
+        }
+        Ok(held)
+    }
+
+    // a few renewals per lease, so one slow round trip doesn't lose it
+    fn hold_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis((self.lease_ms / 3).max(1)))
+    }
+
+    async fn release(&self, queue: &str) -> RedisResult<()> {
+        let dropped: bool = RELEASE_SCRIPT
+            .key(owner_key(queue))
+            .key(queue)
+            .arg(&self.consumer)
+            .arg(&self.group)
+            .invoke_async(&mut self.redis_conn.clone())
+            .await?;
+        if dropped {
+            self.grouped.lock().unwrap().remove(queue);
+        }
+        Ok(())
+    }
+
+    async fn stats(&self, queue: &str) -> RedisResult<QueueStats> {
+        let (depth, oldest, newest): (i64, StreamRangeReply, StreamRangeReply) = redis::pipe()
+            .xlen(queue)
+            .xrange_count(queue, "-", "+", 1)
+            .xrevrange_count(queue, "+", "-", 1)
+            .query_async(&mut self.redis_conn.clone())
+            .await?;
+        Ok(QueueStats {
+            depth,
+            oldest_score: oldest.ids.first().and_then(|entry| entry.get(SCORE_FIELD)),
+            newest_score: newest.ids.first().and_then(|entry| entry.get(SCORE_FIELD)),
+        })
+    }
+
+    // in arrival order, not triggered-at
+    async fn range(
+        &self,
+        queue: &str,
+        range: ScoreRange,
+        offset: u64,
+        count: u64,
+    ) -> RedisResult<Vec<Claimed>> {
+        let within = self
+            .within(queue, range, Some((offset + count) as usize))
+            .await?;
+        Ok(within.into_iter().skip(offset as usize).collect())
+    }
+
+    async fn count(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        Ok(self.within(queue, range, None).await?.len() as u64)
+    }
+
+    // acked too, in case a worker has them pending. Forgotten as well, an import may bring them back
+    async fn remove(&self, queue: &str, range: ScoreRange) -> RedisResult<u64> {
+        let within = self.within(queue, range, None).await?;
+        let mut redis_conn = self.redis_conn.clone();
+        for chunk in within.chunks(SCAN_BATCH) {
+            let ids: Vec<&str> = chunk.iter().filter_map(|c| c.id.as_deref()).collect();
+            let entries: Vec<String> = chunk.iter().map(|c| entry_key(queue, &c.request)).collect();
+            redis::pipe()
+                .atomic()
+                .xack(queue, &self.group, &ids)
+                .xdel(queue, &ids)
+                .del(&entries)
+                .query_async::<_, ()>(&mut redis_conn)
+                .await?;
+        }
+        Ok(within.len() as u64)
+    }
+
+    // a page at a time, each moved in one step, so a long stream doesn't block redis
+    async fn merge(&self, from: &str, to: &str) -> RedisResult<(u64, u64)> {
+        let mut redis_conn = self.redis_conn.clone();
+        let existing: u64 = redis_conn.xlen(to).await?;
+        let mut moved = 0;
+        loop {
+            let page: u64 = MERGE_SCRIPT
+                .key(from)
+                .key(to)
+                .key(owner_key(from))
+                .arg(SCAN_BATCH)
+                .invoke_async(&mut redis_conn)
+                .await?;
+            moved += page;
+            if page < SCAN_BATCH as u64 {
+                return Ok((moved, existing));
+            }
+        }
+    }
+}
+
+fn owner_key(queue: &str) -> String {
+    format!("{}:{queue}", consts::STREAM_OWNER_KEY_PREFIX)
+}
+
+fn entry_key(queue: &str, envelope: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, envelope);
+    format!(
+        "{}:{queue}:{}",
+        consts::STREAM_ENTRY_KEY_PREFIX,
+        data_encoding::HEXLOWER.encode(digest.as_ref())
+    )
+}
+
+// takes (2) or renews (1) the lease when it's free or already ours, 0 when someone else holds it
+static LEASE_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        local owner = redis.call('GET', KEYS[1])
+        if owner and owner ~= ARGV[1] then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+        if owner then
+            return 1
+        end
+        return 2
+        ",
+    )
+});
+
+// lets the lease go, then drops the stream with its group (1) once it's drained and no one else
+// holds it, the receiver's next XADD starts it afresh
+static RELEASE_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+        end
+        if redis.call('EXISTS', KEYS[1]) == 1 or redis.call('XLEN', KEYS[2]) > 0 then
+            return 0
+        end
+        local pending = redis.pcall('XPENDING', KEYS[2], ARGV[2])
+        if pending.err or pending[1] == 0 then
+            redis.call('DEL', KEYS[2])
+            return 1
+        end
+        return 0
+        ",
+    )
+});
+
+// moves a page of the source's entries to the end of the destination, after what's already there,
+// then drops the source with its group and lease once it's empty. Unlike sorted sets, a request in
+// both is kept twice
+static MERGE_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        local entries = redis.call('XRANGE', KEYS[1], '-', '+', 'COUNT', ARGV[1])
+        local ids = {}
+        for _, entry in ipairs(entries) do
+            redis.call('XADD', KEYS[2], '*', unpack(entry[2]))
+            table.insert(ids, entry[1])
+        end
+        if #ids > 0 then
+            redis.call('XDEL', KEYS[1], unpack(ids))
+        end
+        if redis.call('XLEN', KEYS[1]) == 0 then
+            redis.call('DEL', KEYS[1], KEYS[3])
+        end
+        return #ids
+        ",
+    )
+});
+
+// the stream flavour of the sorted sets' enqueue-once script
+static XADD_ONCE_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if redis.call('SET', KEYS[1], 1, 'NX', 'EX', ARGV[1]) then
+            redis.call('XADD', KEYS[2], '*', 'e', ARGV[3], 's', ARGV[2])
+            return 1
+        end
+        redis.call('HINCRBY', KEYS[3], ARGV[4], 1)
+        return 0
+        ",
+    )
+});
+
+// XADDs the envelope unless it was enqueued within the ttl
+static XADD_NEW_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if redis.call('SET', KEYS[1], 1, 'NX', 'EX', ARGV[1]) then
+            redis.call('XADD', KEYS[2], '*', 'e', ARGV[3], 's', ARGV[2])
+            return 1
+        end
+        return 0
+        ",
+    )
+});
+
+#[cfg(test)]
+async fn test_stores(queue: &str, lease_ms: u64) -> (StreamQueueStore, StreamQueueStore) {
+    let mut redis_conn = crate::services::test_redis_conn().await;
+    let mut keys: Vec<String> = redis_conn
+        .keys(format!("{}:{queue}:*", consts::STREAM_ENTRY_KEY_PREFIX))
+        .await
+        .unwrap();
+    keys.extend([queue.to_string(), owner_key(queue)]);
+    redis_conn.del::<_, ()>(&keys).await.unwrap();
+    let store = |consumer| StreamQueueStore::new(redis_conn.clone(), "test", consumer, lease_ms);
+    (store("a"), store("b"))
+}
+
+#[cfg(test)]
+fn test_enqueue(queue: &str, score: i64) -> Enqueue {
+    Enqueue {
+        queue: queue.to_string(),
+        score,
+        envelope: score.to_be_bytes().to_vec(),
+        dedup: None,
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a redis"]
+async fn test_stream_lease() {
+    let queue = "test:stream:lease";
+    let (a, b) = test_stores(queue, 200).await;
+    for score in [1, 2] {
+        a.enqueue(test_enqueue(queue, score)).await.unwrap();
+    }
+    // an entry the receiver didn't write is acked and skipped
+    redis::cmd("XADD")
+        .arg(queue)
+        .arg("*")
+        .arg("x")
+        .arg(1)
+        .query_async::<_, ()>(&mut a.redis_conn.clone())
+        .await
+        .unwrap();
+
+    let claimed = a.claim(queue, 0, 10).await.unwrap();
+    assert_eq!(
+        claimed.iter().map(|c| c.score).collect::<Vec<_>>(),
+        [1.0, 2.0]
+    );
+    assert!(b.claim(queue, 0, 10).await.unwrap().is_empty());
+    assert!(!b.hold(queue).await.unwrap());
+    assert!(a.hold(queue).await.unwrap());
+    a.ack(queue, &claimed[0]).await.unwrap();
+
+    // a stalls past its lease, b takes the queue over with what a left pending
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    b.enqueue(test_enqueue(queue, 3)).await.unwrap();
+    let taken = b.claim(queue, 0, 10).await.unwrap();
+    assert_eq!(taken.iter().map(|c| c.score).collect::<Vec<_>>(), [2.0]);
+    assert!(!a.hold(queue).await.unwrap());
+    // a's delivery stops at the next renewal
+    let work = tokio::time::sleep(Duration::from_secs(5));
+    assert!(super::while_held(&a, queue, work).await.is_none());
+    // then the entries no one read yet
+    b.ack(queue, &taken[0]).await.unwrap();
+    let new = b.claim(queue, 0, 10).await.unwrap();
+    assert_eq!(new.iter().map(|c| c.score).collect::<Vec<_>>(), [3.0]);
+    b.ack(queue, &new[0]).await.unwrap();
+    assert!(b.claim(queue, 0, 10).await.unwrap().is_empty());
+
+    // released drained, the stream is gone with its group
+    b.release(queue).await.unwrap();
+    assert!(!b.queues().await.unwrap().contains(&queue.to_string()));
+    let exists: bool = b.redis_conn.clone().exists(queue).await.unwrap();
+    assert!(!exists);
+}
+
+#[tokio::test]
+#[ignore = "needs a redis"]
+async fn test_stream_recover() {
+    let queue = "test:stream:recover";
+    let (a, b) = test_stores(queue, 100).await;
+    let total = RECOVER_BATCH + 10;
+    let mut pipe = redis::pipe();
+    for score in 0..total {
+        pipe.xadd(
+            queue,
+            "*",
+            &[
+                (ENVELOPE_FIELD, (score as i64).to_be_bytes().to_vec()),
+                (SCORE_FIELD, score.to_string().into_bytes()),
+            ],
+        );
+    }
+    pipe.query_async::<_, ()>(&mut a.redis_conn.clone())
+        .await
+        .unwrap();
+    assert_eq!(a.claim(queue, 0, total as u64).await.unwrap().len(), total);
+
+    // every pending entry is taken over, not only the first XPENDING page
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let claimed = b.claim(queue, 0, 10).await.unwrap();
+    assert_eq!(
+        claimed.iter().map(|c| c.score).collect::<Vec<_>>(),
+        (0..10).map(f64::from).collect::<Vec<_>>()
+    );
+    let pending: StreamPendingCountReply = b
+        .redis_conn
+        .clone()
+        .xpending_consumer_count(queue, "test", "-", "+", total * 2, "b")
+        .await
+        .unwrap();
+    assert_eq!(pending.ids.len(), total);
+    b.redis_conn
+        .clone()
+        .del::<_, ()>(&[queue.to_string(), owner_key(queue)])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a redis"]
+async fn test_stream_admin() {
+    let queue = "test:stream:admin";
+    let other = "test:stream:admin-other";
+    let (a, _) = test_stores(queue, 1000).await;
+    test_stores(other, 1000).await;
+    for score in [3, 1, 2] {
+        assert!(a.enqueue(test_enqueue(queue, score)).await.unwrap());
+    }
+    a.enqueue(test_enqueue(other, 5)).await.unwrap();
+    // e.g. replayed again from the spool after a crash
+    assert!(!a.enqueue(test_enqueue(queue, 3)).await.unwrap());
+    assert_eq!(a.stats(queue).await.unwrap().depth, 3);
+
+    let up_to_2 = ScoreRange {
+        min: None,
+        max: Some(2),
+    };
+    // arrival order, not score order
+    let range = a.range(queue, up_to_2, 0, 10).await.unwrap();
+    assert_eq!(
+        range.iter().map(|c| c.score).collect::<Vec<_>>(),
+        [1.0, 2.0]
+    );
+    assert_eq!(
+        a.range(queue, ScoreRange::default(), 1, 1).await.unwrap()[0].score,
+        1.0
+    );
+    assert_eq!(a.count(queue, up_to_2).await.unwrap(), 2);
+    assert_eq!(a.remove(queue, up_to_2).await.unwrap(), 2);
+    assert_eq!(a.stats(queue).await.unwrap().depth, 1);
+
+    assert_eq!(a.merge(other, queue).await.unwrap(), (1, 1));
+    let merged = a.range(queue, ScoreRange::default(), 0, 10).await.unwrap();
+    assert_eq!(
+        merged.iter().map(|c| c.score).collect::<Vec<_>>(),
+        [3.0, 5.0]
+    );
+    let exists: bool = a.redis_conn.clone().exists(other).await.unwrap();
+    assert!(!exists);
+
+    // removed ones may be imported again
+    assert_eq!(a.remove(queue, ScoreRange::default()).await.unwrap(), 2);
+    assert!(a.enqueue(test_enqueue(queue, 3)).await.unwrap());
+    a.redis_conn.clone().del::<_, ()>(queue).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a redis"]
+async fn test_stream_merge_pages() {
+    let queue = "test:stream:merge";
+    let other = "test:stream:merge-other";
+    let (a, _) = test_stores(queue, 1000).await;
+    test_stores(other, 1000).await;
+    a.enqueue(test_enqueue(queue, 0)).await.unwrap();
+    let batch = (1..=2 * SCAN_BATCH as i64 + 1)
+        .map(|score| test_enqueue(other, score))
+        .collect();
+    a.enqueue_batch(batch).await.unwrap();
+
+    assert_eq!(
+        a.merge(other, queue).await.unwrap(),
+        (2 * SCAN_BATCH as u64 + 1, 1)
+    );
+    let merged = a.range(queue, ScoreRange::default(), 0, 5).await.unwrap();
+    assert_eq!(
+        merged.iter().map(|c| c.score).collect::<Vec<_>>(),
+        [0.0, 1.0, 2.0, 3.0, 4.0]
+    );
+    assert_eq!(
+        a.stats(queue).await.unwrap().depth,
+        2 * SCAN_BATCH as i64 + 2
+    );
+    let exists: bool = a.redis_conn.clone().exists(other).await.unwrap();
+    assert!(!exists);
+    a.redis_conn.clone().del::<_, ()>(queue).await.unwrap();
+}
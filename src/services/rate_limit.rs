@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use redis::aio::ConnectionManager;
+
+// takes a token from the bucket at `key`. None when one was available, otherwise how long until
+// the next one. Buckets live in redis so every receiver process shares them
+pub async fn acquire(
+    redis_conn: &mut ConnectionManager,
+    key: &str,
+    rate_per_sec: f64,
+    burst: u64,
+) -> redis::RedisResult<Option<Duration>> {
+    let wait_ms: u64 = TOKEN_BUCKET_SCRIPT
+        .key(key)
+        .arg(rate_per_sec)
+        .arg(burst)
+        .invoke_async(redis_conn)
+        .await?;
+    Ok((wait_ms > 0).then(|| Duration::from_millis(wait_ms)))
+}
+
+// refills the bucket for the time elapsed since its last use (by redis' clock, receivers' clocks
+// may drift), then takes a token or returns the milliseconds until one is available. Idle buckets
+// expire once they would be full again
+static TOKEN_BUCKET_SCRIPT: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        local rate = tonumber(ARGV[1])
+        local burst = tonumber(ARGV[2])
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+        local tokens = tonumber(bucket[1]) or burst
+        local ts = tonumber(bucket[2]) or now
+        tokens = math.min(burst, tokens + math.max(0, now - ts) * rate / 1000)
+        local wait = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+        else
+            wait = math.ceil((1 - tokens) * 1000 / rate)
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+        redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / rate) + 1000)
+        return wait
+        ",
+    )
+});
+
+#[tokio::test]
+#[ignore = "needs a redis"]
+async fn test_token_bucket() {
+    use redis::AsyncCommands;
+
+    let mut redis_conn = super::test_redis_conn().await;
+    let key = "test:rate-limit:a.myshopify.com";
+    redis_conn.del::<_, ()>(key).await.unwrap();
+    // a full bucket of 2, refilled at 10 per second
+    assert_eq!(acquire(&mut redis_conn, key, 10.0, 2).await.unwrap(), None);
+    assert_eq!(acquire(&mut redis_conn, key, 10.0, 2).await.unwrap(), None);
+    let wait = acquire(&mut redis_conn, key, 10.0, 2)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(wait <= Duration::from_millis(100), "{wait:?}");
+    // the denied take doesn't cost a token, one is back after 100ms
+    tokio::time::sleep(Duration::from_millis(110)).await;
+    assert_eq!(acquire(&mut redis_conn, key, 10.0, 2).await.unwrap(), None);
+    assert!(acquire(&mut redis_conn, key, 10.0, 2)
+        .await
+        .unwrap()
+        .is_some());
+    // idle buckets expire once full again
+    let ttl: i64 = redis_conn.pttl(key).await.unwrap();
+    assert!((1000..=1200).contains(&ttl), "{ttl}");
+    redis_conn.del::<_, ()>(key).await.unwrap();
+}
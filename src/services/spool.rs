@@ -0,0 +1,557 @@
+use anyhow::{anyhow, Context};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::queue_store::{Enqueue, QueueStore};
+use crate::common::metrics;
+
+// appended to while redis is unreachable
+const SPOOL_FILE: &str = "spool.log";
+// the spool file being replayed, new writes go to a fresh spool file meanwhile
+const REPLAY_FILE: &str = "spool.replay.log";
+// locked while a receiver uses the directory, another one would rotate files under its feet
+const LOCK_FILE: &str = "spool.lock";
+// record: magic, payload length and crc32 (little endian u32s), then the bitcode encoded Enqueue
+const RECORD_MAGIC: u32 = 0x5350_4c31;
+const HEADER_LEN: usize = 12;
+// anything longer is a corrupt length, not a webhook
+const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+// records read from the replay file at a time
+const REPLAY_CHUNK: usize = 500;
+
+// replayable records with the offset past each, and why reading stopped early if it did
+type Chunk = (Vec<(Enqueue, u64)>, Option<String>);
+
+#[derive(Debug, Default)]
+struct Depth {
+    records: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    current: Depth,
+    replaying: Depth,
+    // position in the replay file up to which records are in redis, only kept in memory: a
+    // restarted receiver replays the file from the start
+    replayed_to: u64,
+}
+
+impl State {
+    fn observe(&self) {
+        metrics::SPOOL_RECORDS.set((self.current.records + self.replaying.records) as i64);
+        metrics::SPOOL_BYTES.set((self.current.bytes + self.replaying.bytes) as i64);
+    }
+}
+
+// an append-only, fsync'd local file of the enqueues that failed because redis was unreachable,
+// replayed into redis in order once it's back
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<State>,
+    // released when the process exits, however it exits
+    _lock: File,
+}
+
+impl Spool {
+    // counts what a previous process left behind. A torn record at the end of the spool file (a
+    // crash mid-write) is set aside so appends start on a record boundary
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create spool directory {dir:?}"))?;
+        let lock = File::create(dir.join(LOCK_FILE))
+            .with_context(|| format!("failed to open the lock of spool directory {dir:?}"))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                return Err(anyhow!(
+                    "spool directory {dir:?} is used by another process, each receiver needs its own"
+                ));
+            }
+            Err(std::fs::TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("failed to lock spool directory {dir:?}"));
+            }
+        }
+        let replaying = match scan(&dir.join(REPLAY_FILE)) {
+            Ok((depth, _)) => depth,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Depth::default(),
+            Err(e) => return Err(e).context("failed to read the spool replay file"),
+        };
+        let path = dir.join(SPOOL_FILE);
+        let file = open_append(&path)?;
+        let (current, valid_len) = scan(&path).context("failed to read the spool file")?;
+        if valid_len < file.metadata()?.len() {
+            set_aside(&dir, &path, valid_len, None)?;
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        if current.records + replaying.records > 0 {
+            tracing::warn!(
+                "{} webhooks spooled in {dir:?}, replayed once redis is reachable",
+                current.records + replaying.records
+            );
+        }
+        let state = State {
+            file,
+            current,
+            replaying,
+            replayed_to: 0,
+        };
+        state.observe();
+        Ok(Self {
+            dir,
+            max_bytes,
+            state: Mutex::new(state),
+            _lock: lock,
+        })
+    }
+
+    // returns once the record is on disk
+    pub async fn append(self: &Arc<Self>, enqueue: &Enqueue) -> anyhow::Result<()> {
+        let record = encode_record(enqueue)?;
+        let spool = self.clone();
+        tokio::task::spawn_blocking(move || spool.append_blocking(&record)).await?
+    }
+
+    fn append_blocking(&self, record: &[u8]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let len = record.len() as u64;
+        if state.current.bytes + state.replaying.bytes + len > self.max_bytes {
+            return Err(anyhow!("spool is full ({} bytes)", self.max_bytes));
+        }
+        let res = state
+            .file
+            .write_all(record)
+            .and_then(|_| state.file.sync_data());
+        if let Err(e) = res {
+            // don't leave a torn record for the next append to follow
+            let _ = state.file.set_len(state.current.bytes);
+            return Err(e).context("failed to write to the spool");
+        }
+        state.current.records += 1;
+        state.current.bytes += len;
+        state.observe();
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.current.records + state.replaying.records == 0
+    }
+
+    // moves what was spooled so far to the replay file, unless one is still being replayed
+    fn rotate(&self) -> anyhow::Result<Option<u64>> {
+        let mut state = self.state.lock().unwrap();
+        let replay_path = self.dir.join(REPLAY_FILE);
+        if replay_path.exists() {
+            return Ok(Some(state.replayed_to));
+        }
+        if state.current.records == 0 {
+            return Ok(None);
+        }
+        let path = self.dir.join(SPOOL_FILE);
+        state.file.sync_all()?;
+        std::fs::rename(&path, &replay_path)?;
+        state.file = open_append(&path)?;
+        state.replaying = std::mem::take(&mut state.current);
+        state.replayed_to = 0;
+        Ok(Some(0))
+    }
+
+    fn read_chunk(&self, offset: u64) -> std::io::Result<Chunk> {
+        let mut reader = BufReader::new(File::open(self.dir.join(REPLAY_FILE))?);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut records = Vec::new();
+        let mut position = offset;
+        while records.len() < REPLAY_CHUNK {
+            match read_record(&mut reader) {
+                Ok(Some(payload)) => {
+                    position += (HEADER_LEN + payload.len()) as u64;
+                    match bitcode::deserialize::<Enqueue>(&payload) {
+                        Ok(enqueue) => records.push((enqueue, position)),
+                        Err(e) => return Ok((records, Some(e.to_string()))),
+                    }
+                }
+                Ok(None) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    return Ok((records, Some(e.to_string())));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((records, None))
+    }
+
+    fn replayed(&self, to: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replaying.records = state.replaying.records.saturating_sub(1);
+        state.replaying.bytes = state.replaying.bytes.saturating_sub(to - state.replayed_to);
+        state.replayed_to = to;
+        state.observe();
+    }
+
+    fn finish_replay(&self, corrupt_from: Option<u64>) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let path = self.dir.join(REPLAY_FILE);
+        if let Some(offset) = corrupt_from {
+            set_aside(&self.dir, &path, offset, None)?;
+        }
+        std::fs::remove_file(&path)?;
+        state.replaying = Depth::default();
+        state.replayed_to = 0;
+        state.observe();
+        Ok(())
+    }
+
+    // pushes the spooled enqueues to redis, oldest first. Stops at the first one redis is
+    // unreachable for, which is retried with the next replay. One redis refuses (e.g. WRONGTYPE
+    // after a QUEUE_BACKEND switch) is set aside instead, so it doesn't hold up the rest. Records
+    // replayed twice (after a crash) aren't queued twice: a sorted set holds a request once, a
+    // stream remembers the envelopes it took (stream-entry keys) and deduplicated webhooks are
+    // only counted as duplicates
+    pub async fn replay(self: &Arc<Self>, store: &impl QueueStore) -> anyhow::Result<u64> {
+        let Some(mut offset) = self.rotate()? else {
+            return Ok(0);
+        };
+        let mut count = 0;
+        loop {
+            let spool = self.clone();
+            let (records, corrupt) =
+                tokio::task::spawn_blocking(move || spool.read_chunk(offset)).await??;
+            let done = records.len() < REPLAY_CHUNK || corrupt.is_some();
+            for (enqueue, next) in records {
+                match store.enqueue(enqueue).await {
+                    Ok(_) => {
+                        metrics::SPOOL_REPLAYED.inc();
+                        count += 1;
+                    }
+                    Err(e) if unreachable(&e) => return Err(e.into()),
+                    Err(e) => {
+                        tracing::error!("spooled webhook at {offset} refused, set aside: {e:?}");
+                        set_aside(&self.dir, &self.dir.join(REPLAY_FILE), offset, Some(next))?;
+                        metrics::SPOOL_REFUSED.inc();
+                    }
+                }
+                self.replayed(next);
+                offset = next;
+            }
+            if let Some(reason) = &corrupt {
+                tracing::error!("corrupt spool record at {offset}, set aside: {reason}");
+                metrics::SPOOL_CORRUPT.inc();
+            }
+            if done {
+                self.finish_replay(corrupt.map(|_| offset))?;
+                return Ok(count);
+            }
+        }
+    }
+
+    // replays every `interval` seconds while anything is spooled
+    pub async fn watch(self: Arc<Self>, store: impl QueueStore, interval: u64) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+        loop {
+            ticker.tick().await;
+            if self.is_empty() {
+                continue;
+            }
+            match self.replay(&store).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("replayed {count} spooled webhooks into redis"),
+                Err(e) => tracing::warn!("spool replay interrupted: {e:?}"),
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open spool file {path:?}"))?;
+    // the directory entry of a new file must survive a crash too
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(file)
+}
+
+// copies the bytes from `offset` (to `end`, or the end of the file) to a
+// spool.corrupt.<timestamp>.<offset>.log file for inspection
+fn set_aside(dir: &Path, path: &Path, offset: u64, end: Option<u64>) -> anyhow::Result<()> {
+    let mut src = File::open(path)?;
+    src.seek(SeekFrom::Start(offset))?;
+    // several records of one replay may be set aside within the same millisecond
+    let aside = dir.join(format!(
+        "spool.corrupt.{}.{offset}.log",
+        chrono::Utc::now().timestamp_millis()
+    ));
+    let mut dst = File::create(&aside)?;
+    let bytes = match end {
+        Some(end) => std::io::copy(&mut src.take(end - offset), &mut dst)?,
+        None => std::io::copy(&mut src, &mut dst)?,
+    };
+    dst.sync_all()?;
+    tracing::error!("set aside {bytes} spool bytes of {path:?} in {aside:?}");
+    Ok(())
+}
+
+// redis being down, failing over or still loading, as opposed to rejecting the write
+pub fn unreachable(e: &redis::RedisError) -> bool {
+    e.is_io_error()
+        || e.is_connection_refusal()
+        || e.is_connection_dropped()
+        || e.is_timeout()
+        || matches!(
+            e.kind(),
+            redis::ErrorKind::BusyLoadingError
+                | redis::ErrorKind::TryAgain
+                | redis::ErrorKind::ClusterDown
+                | redis::ErrorKind::MasterDown
+                | redis::ErrorKind::ReadOnly
+        )
+}
+
+fn encode_record(enqueue: &Enqueue) -> anyhow::Result<Vec<u8>> {
+    let payload = bitcode::serialize(enqueue)?;
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend(RECORD_MAGIC.to_le_bytes());
+    record.extend((payload.len() as u32).to_le_bytes());
+    record.extend(crc32fast::hash(&payload).to_le_bytes());
+    record.extend(payload);
+    Ok(record)
+}
+
+// None at a clean end of file, InvalidData for torn or corrupt records
+fn read_record(reader: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>> {
+    let corrupt = |reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, reason);
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut header = [0; HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| corrupt("torn record header"))?;
+    let field = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+    if field(0) != RECORD_MAGIC {
+        return Err(corrupt("bad record magic"));
+    }
+    let len = field(1);
+    if len > MAX_RECORD_LEN {
+        return Err(corrupt("bad record length"));
+    }
+    let mut payload = vec![0; len as usize];
+    reader
+        .read_exact(&mut payload)
+        .map_err(|_| corrupt("torn record"))?;
+    if crc32fast::hash(&payload) != field(2) {
+        return Err(corrupt("record checksum mismatch"));
+    }
+    Ok(Some(payload))
+}
+
+// the records of a spool file, and the length up to the first unreadable one
+fn scan(path: &Path) -> std::io::Result<(Depth, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut depth = Depth::default();
+    loop {
+        match read_record(&mut reader) {
+            Ok(Some(payload)) => {
+                depth.records += 1;
+                depth.bytes += (HEADER_LEN + payload.len()) as u64;
+            }
+            Ok(None) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                tracing::error!("corrupt spool record in {path:?}: {e}");
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let valid_len = depth.bytes;
+    Ok((depth, valid_len))
+}
+
+#[test]
+fn test_spool_records() {
+    let dir = std::env::temp_dir().join(format!("webhook-spool-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let enqueue = |score| Enqueue {
+        queue: "default:a.myshopify.com:orders/create".to_string(),
+        score,
+        envelope: vec![1, 2, 3],
+        dedup: None,
+    };
+    let spool = Spool::open(&dir, 1 << 20).unwrap();
+    // one process per directory
+    assert!(Spool::open(&dir, 1 << 20).is_err());
+    for score in [1, 2] {
+        spool
+            .append_blocking(&encode_record(&enqueue(score)).unwrap())
+            .unwrap();
+    }
+    assert!(!spool.is_empty());
+    assert_eq!(spool.rotate().unwrap(), Some(0));
+    let (records, corrupt) = spool.read_chunk(0).unwrap();
+    assert_eq!(corrupt, None);
+    assert_eq!(
+        records.iter().map(|(e, _)| e.score).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    drop(spool);
+
+    // flip a payload byte of the second record, and tear a third one
+    let path = dir.join(REPLAY_FILE);
+    let mut raw = std::fs::read(&path).unwrap();
+    let last = raw.len() - 1;
+    raw[last] ^= 0xff;
+    std::fs::write(&path, &raw).unwrap();
+    let mut torn = encode_record(&enqueue(3)).unwrap();
+    torn.truncate(HEADER_LEN + 1);
+    std::fs::write(dir.join(SPOOL_FILE), &torn).unwrap();
+
+    let spool = Spool::open(&dir, 1 << 20).unwrap();
+    let state = spool.state.lock().unwrap();
+    assert_eq!(state.replaying.records, 1);
+    assert_eq!(state.current.records, 0);
+    assert_eq!(std::fs::metadata(dir.join(SPOOL_FILE)).unwrap().len(), 0);
+    drop(state);
+    let (records, corrupt) = spool.read_chunk(0).unwrap();
+    assert_eq!(records.len(), 1);
+    assert!(corrupt.is_some());
+
+    // the readable record goes to the store, the rest is set aside
+    let store = super::queue_store::MemoryQueueStore::new();
+    let spool = Arc::new(spool);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    assert_eq!(runtime.block_on(spool.replay(&store)).unwrap(), 1);
+    let queues = runtime.block_on(store.queues()).unwrap();
+    assert_eq!(queues, vec![enqueue(1).queue]);
+    assert!(spool.is_empty());
+    assert!(!dir.join(REPLAY_FILE).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// refuses enqueues into one queue, like redis does once its key holds another type
+#[cfg(test)]
+#[derive(Clone)]
+struct RefusingStore {
+    refused: String,
+    store: super::queue_store::MemoryQueueStore,
+}
+
+#[cfg(test)]
+impl QueueStore for RefusingStore {
+    async fn enqueue(&self, enqueue: Enqueue) -> redis::RedisResult<bool> {
+        if enqueue.queue == self.refused {
+            return Err((redis::ErrorKind::ResponseError, "WRONGTYPE").into());
+        }
+        self.store.enqueue(enqueue).await
+    }
+
+    async fn queues(&self) -> redis::RedisResult<Vec<String>> {
+        self.store.queues().await
+    }
+
+    async fn claim(
+        &self,
+        queue: &str,
+        offset: u64,
+        count: u64,
+    ) -> redis::RedisResult<Vec<super::queue_store::Claimed>> {
+        self.store.claim(queue, offset, count).await
+    }
+
+    async fn ack(
+        &self,
+        queue: &str,
+        claimed: &super::queue_store::Claimed,
+    ) -> redis::RedisResult<()> {
+        self.store.ack(queue, claimed).await
+    }
+
+    async fn stats(&self, queue: &str) -> redis::RedisResult<super::queue_store::QueueStats> {
+        self.store.stats(queue).await
+    }
+
+    async fn range(
+        &self,
+        queue: &str,
+        range: super::queue_store::ScoreRange,
+        offset: u64,
+        count: u64,
+    ) -> redis::RedisResult<Vec<super::queue_store::Claimed>> {
+        self.store.range(queue, range, offset, count).await
+    }
+
+    async fn count(
+        &self,
+        queue: &str,
+        range: super::queue_store::ScoreRange,
+    ) -> redis::RedisResult<u64> {
+        self.store.count(queue, range).await
+    }
+
+    async fn remove(
+        &self,
+        queue: &str,
+        range: super::queue_store::ScoreRange,
+    ) -> redis::RedisResult<u64> {
+        self.store.remove(queue, range).await
+    }
+
+    async fn merge(&self, from: &str, to: &str) -> redis::RedisResult<(u64, u64)> {
+        self.store.merge(from, to).await
+    }
+}
+
+#[test]
+fn test_spool_replay_refused() {
+    let dir = std::env::temp_dir().join(format!("webhook-spool-refused-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let enqueue = |queue: &str| Enqueue {
+        queue: queue.to_string(),
+        score: 1,
+        envelope: vec![1, 2, 3],
+        dedup: None,
+    };
+    let spool = Arc::new(Spool::open(&dir, 1 << 20).unwrap());
+    for queue in ["a:orders/create", "b:orders/create", "c:orders/create"] {
+        spool
+            .append_blocking(&encode_record(&enqueue(queue)).unwrap())
+            .unwrap();
+    }
+    let store = RefusingStore {
+        refused: "b:orders/create".to_string(),
+        store: super::queue_store::MemoryQueueStore::new(),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    // the refused record doesn't hold up the one behind it
+    assert_eq!(runtime.block_on(spool.replay(&store)).unwrap(), 2);
+    let mut queues = runtime.block_on(store.queues()).unwrap();
+    queues.sort();
+    assert_eq!(queues, vec!["a:orders/create", "c:orders/create"]);
+    assert!(spool.is_empty());
+    let aside: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().contains("spool.corrupt."))
+        .collect();
+    assert_eq!(aside.len(), 1);
+    let raw = std::fs::read(&aside[0]).unwrap();
+    let payload = read_record(&mut raw.as_slice()).unwrap().unwrap();
+    let refused: Enqueue = bitcode::deserialize(&payload).unwrap();
+    assert_eq!(refused.queue, "b:orders/create");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
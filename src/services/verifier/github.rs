@@ -0,0 +1,70 @@
+use chrono::Utc;
+use data_encoding::HEXLOWER_PERMISSIVE;
+
+use super::{header_str, EventMeta, WebhookVerifier};
+use crate::common::{consts, crypt};
+use crate::config::ClientSecret;
+use crate::model::error::WebhookError;
+use crate::model::ReqDownstream;
+
+// "sha256=<hex hmac of the body>" in x-hub-signature-256
+#[derive(Debug, Clone, Copy)]
+pub struct GithubVerifier;
+
+impl WebhookVerifier for GithubVerifier {
+    fn verify<'a>(
+        &self,
+        secrets: &'a [ClientSecret],
+        request: &ReqDownstream,
+    ) -> Result<&'a ClientSecret, WebhookError> {
+        let signature = header_str(&request.headers, consts::XHUB_SIGNATURE_256)?;
+        let digest = signature
+            .strip_prefix("sha256=")
+            .and_then(|hex| HEXLOWER_PERMISSIVE.decode(hex.as_bytes()).ok())
+            .ok_or(WebhookError::BadSignature)?;
+        crypt::hmac_256_find(secrets, &[&request.payload], &digest)
+            .ok_or(WebhookError::BadSignature)
+    }
+
+    // github doesn't send when the event happened, so events are ordered by arrival. The queue is
+    // per installation target (repository, organization or app) and event
+    fn event_meta(&self, request: &ReqDownstream) -> Result<EventMeta, WebhookError> {
+        let target = header_str(&request.headers, consts::XGITHUB_HOOK_TARGET_ID)?;
+        let event = header_str(&request.headers, consts::XGITHUB_EVENT)?;
+        let delivery = header_str(&request.headers, consts::XGITHUB_DELIVERY)
+            .ok()
+            .map(str::to_string);
+        Ok(EventMeta {
+            source: target.to_string(),
+            topic: event.to_string(),
+            triggered_at: Utc::now(),
+            event_id: delivery,
+        })
+    }
+}
+
+#[test]
+fn test_github_verify() {
+    use super::{sign_hex, test_request, test_secrets};
+    let body = r#"{"zen":"Keep it logically awesome."}"#;
+    let signature = format!("sha256={}", sign_hex("s3cr3t", body.as_bytes()));
+    let req = test_request(
+        &[
+            (consts::XHUB_SIGNATURE_256, &signature),
+            (consts::XGITHUB_EVENT, "ping"),
+            (consts::XGITHUB_HOOK_TARGET_ID, "42"),
+            (
+                consts::XGITHUB_DELIVERY,
+                "72d3162e-cc78-11e3-81ab-4c9367dc0958",
+            ),
+        ],
+        body,
+    );
+    let secrets = test_secrets();
+    assert_eq!(GithubVerifier.verify(&secrets, &req).unwrap().id, "k1");
+    let meta = GithubVerifier.event_meta(&req).unwrap();
+    assert_eq!((meta.source.as_str(), meta.topic.as_str()), ("42", "ping"));
+
+    let tampered = test_request(&[(consts::XHUB_SIGNATURE_256, &signature)], "{}");
+    assert!(GithubVerifier.verify(&secrets, &tampered).is_err());
+}
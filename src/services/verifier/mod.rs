@@ -0,0 +1,113 @@
+use chrono::{DateTime, Utc};
+
+use crate::config::{AppConfig, ClientSecret, Provider};
+use crate::model::error::WebhookError;
+use crate::model::ReqDownstream;
+
+mod github;
+mod shopify;
+mod slack;
+mod stripe;
+
+pub use github::GithubVerifier;
+pub use shopify::ShopifyVerifier;
+pub use slack::SlackVerifier;
+pub use stripe::StripeVerifier;
+
+// what the ingestion path needs to know about an event, besides the request itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventMeta {
+    // whoever the event is about (shop domain, github installation target, stripe account, slack
+    // team), first half of the queue key
+    pub source: String,
+    pub topic: String,
+    // orders events within a queue
+    pub triggered_at: DateTime<Utc>,
+    // stable across the provider's retries, used for deduplication
+    pub event_id: Option<String>,
+}
+
+pub trait WebhookVerifier: Send + Sync {
+    // checks the request's signature against the app's secrets, returns the matching one
+    fn verify<'a>(
+        &self,
+        secrets: &'a [ClientSecret],
+        request: &ReqDownstream,
+    ) -> Result<&'a ClientSecret, WebhookError>;
+
+    fn event_meta(&self, request: &ReqDownstream) -> Result<EventMeta, WebhookError>;
+
+    // the reply to a signed endpoint check (slack's url_verification), answered instead of queued
+    fn challenge(&self, _request: &ReqDownstream) -> Option<String> {
+        None
+    }
+}
+
+pub fn for_app(app: &AppConfig) -> Box<dyn WebhookVerifier> {
+    let tolerance = chrono::TimeDelta::try_seconds(app.signature_tolerance as i64)
+        .unwrap_or(chrono::TimeDelta::max_value());
+    match app.provider {
+        Provider::Shopify => Box::new(ShopifyVerifier),
+        Provider::Github => Box::new(GithubVerifier),
+        Provider::Stripe => Box::new(StripeVerifier { tolerance }),
+        Provider::Slack => Box::new(SlackVerifier { tolerance }),
+    }
+}
+
+pub(crate) fn header_str<'a>(
+    headers: &'a http::HeaderMap,
+    name: &'static str,
+) -> Result<&'a str, WebhookError> {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .ok_or(WebhookError::MissingHeader(name))
+}
+
+// unix seconds of a signed timestamp, rejected when too far from now to prevent replays
+fn check_timestamp(
+    timestamp: &str,
+    tolerance: chrono::TimeDelta,
+) -> Result<DateTime<Utc>, WebhookError> {
+    let signed_at = timestamp
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+        .ok_or(WebhookError::BadSignature)?;
+    if (Utc::now() - signed_at).abs() > tolerance {
+        return Err(WebhookError::BadSignature);
+    }
+    Ok(signed_at)
+}
+
+#[cfg(test)]
+pub(crate) fn sign_hex(secret: &str, message: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    data_encoding::HEXLOWER.encode(ring::hmac::sign(&key, message).as_ref())
+}
+
+#[cfg(test)]
+pub(crate) fn test_request(headers: &[(&'static str, &str)], payload: &str) -> ReqDownstream {
+    let mut header_map = http::HeaderMap::new();
+    for (name, value) in headers {
+        header_map.insert(*name, value.parse().unwrap());
+    }
+    ReqDownstream {
+        endpoint: "/webhook/app/events/all".to_string(),
+        method: http::Method::POST,
+        headers: header_map,
+        queries: Default::default(),
+        payload: bytes::Bytes::copy_from_slice(payload.as_bytes()),
+        key_id: None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_secrets() -> Vec<ClientSecret> {
+    vec![ClientSecret {
+        id: "k1".to_string(),
+        secret: "s3cr3t".to_string(),
+        not_before: None,
+        not_after: None,
+    }]
+}
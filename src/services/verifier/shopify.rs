@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+
+use super::{header_str, EventMeta, WebhookVerifier};
+use crate::common::{consts, crypt};
+use crate::config::ClientSecret;
+use crate::model::error::WebhookError;
+use crate::model::ReqDownstream;
+
+// base64 hmac-sha256 of the body in x-shopify-hmac-sha256
+#[derive(Debug, Clone, Copy)]
+pub struct ShopifyVerifier;
+
+impl WebhookVerifier for ShopifyVerifier {
+    fn verify<'a>(
+        &self,
+        secrets: &'a [ClientSecret],
+        request: &ReqDownstream,
+    ) -> Result<&'a ClientSecret, WebhookError> {
+        let hmac_sig = header_str(&request.headers, consts::XSHOPIFY_HMAC_SHA256)?;
+        crypt::hmac_256_verify(secrets, &request.payload, hmac_sig).map_err(|e| {
+            tracing::debug!("hmac verification failed: {e:?}");
+            WebhookError::BadSignature
+        })
+    }
+
+    fn event_meta(&self, request: &ReqDownstream) -> Result<EventMeta, WebhookError> {
+        let shop = header_str(&request.headers, consts::XSHOPIFY_SHOP_DOMAIN)?;
+        let topic = header_str(&request.headers, consts::XSHOPIFY_TOPIC)?;
+        // events without a usable timestamp are treated as too old
+        let triggered_at = header_str(&request.headers, consts::XSHOPIFY_TRIGGERED_AT)
+            .ok()
+            .and_then(|h| h.parse::<DateTime<Utc>>().ok())
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let event_id = header_str(&request.headers, consts::XSHOPIFY_WEBHOOK_ID)
+            .ok()
+            .map(str::to_string);
+        Ok(EventMeta {
+            source: shop.to_string(),
+            topic: topic.to_string(),
+            triggered_at,
+            event_id,
+        })
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, TimeDelta};
+use data_encoding::HEXLOWER_PERMISSIVE;
+use serde::Deserialize;
+
+use super::{check_timestamp, header_str, EventMeta, WebhookVerifier};
+use crate::common::{consts, crypt};
+use crate::config::ClientSecret;
+use crate::model::error::WebhookError;
+use crate::model::ReqDownstream;
+
+// "v0=<hex hmac of 'v0:{x-slack-request-timestamp}:{body}'>" in x-slack-signature
+#[derive(Debug, Clone, Copy)]
+pub struct SlackVerifier {
+    pub tolerance: TimeDelta,
+}
+
+// the Events API's outer event
+#[derive(Deserialize)]
+struct SlackEnvelope {
+    #[serde(default)]
+    team_id: Option<String>,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    event_id: Option<String>,
+    #[serde(default)]
+    event_time: Option<i64>,
+    #[serde(default)]
+    event: Option<SlackEvent>,
+    // url_verification only
+    #[serde(default)]
+    challenge: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SlackEvent {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl WebhookVerifier for SlackVerifier {
+    fn verify<'a>(
+        &self,
+        secrets: &'a [ClientSecret],
+        request: &ReqDownstream,
+    ) -> Result<&'a ClientSecret, WebhookError> {
+        let timestamp = header_str(&request.headers, consts::XSLACK_REQUEST_TIMESTAMP)?;
+        let signature = header_str(&request.headers, consts::XSLACK_SIGNATURE)?;
+        check_timestamp(timestamp, self.tolerance)?;
+        let digest = signature
+            .strip_prefix("v0=")
+            .and_then(|hex| HEXLOWER_PERMISSIVE.decode(hex.as_bytes()).ok())
+            .ok_or(WebhookError::BadSignature)?;
+        let message: [&[u8]; 4] = [b"v0:", timestamp.as_bytes(), b":", &request.payload];
+        crypt::hmac_256_find(secrets, &message, &digest).ok_or(WebhookError::BadSignature)
+    }
+
+    // queued per workspace and inner event type, ordered by event_time, or by the request's
+    // timestamp for callbacks without one
+    fn event_meta(&self, request: &ReqDownstream) -> Result<EventMeta, WebhookError> {
+        let envelope: SlackEnvelope = serde_json::from_slice(&request.payload)
+            .map_err(|e| WebhookError::MalformedPayload(e.to_string()))?;
+        let event_time = match envelope.event_time {
+            Some(secs) => secs,
+            None => header_str(&request.headers, consts::XSLACK_REQUEST_TIMESTAMP)?
+                .parse()
+                .map_err(|_| WebhookError::MissingHeader(consts::XSLACK_REQUEST_TIMESTAMP))?,
+        };
+        let triggered_at = DateTime::from_timestamp(event_time, 0)
+            .ok_or_else(|| WebhookError::MalformedPayload("invalid event_time".to_string()))?;
+        Ok(EventMeta {
+            source: envelope.team_id.unwrap_or_else(|| "unknown".to_string()),
+            topic: envelope.event.map_or(envelope.kind, |event| event.kind),
+            triggered_at,
+            event_id: envelope.event_id,
+        })
+    }
+
+    fn challenge(&self, request: &ReqDownstream) -> Option<String> {
+        let envelope: SlackEnvelope = serde_json::from_slice(&request.payload).ok()?;
+        (envelope.kind == "url_verification")
+            .then_some(envelope.challenge)
+            .flatten()
+    }
+}
+
+#[test]
+fn test_slack_verify() {
+    use super::{sign_hex, test_request, test_secrets};
+    let body = r#"{"team_id":"T1","type":"event_callback","event_id":"Ev1","event_time":1714990000,"event":{"type":"app_mention"}}"#;
+    let verifier = SlackVerifier {
+        tolerance: TimeDelta::try_minutes(5).unwrap(),
+    };
+    let ts = chrono::Utc::now().timestamp().to_string();
+    let signature = format!(
+        "v0={}",
+        sign_hex("s3cr3t", format!("v0:{ts}:{body}").as_bytes())
+    );
+    let req = test_request(
+        &[
+            (consts::XSLACK_REQUEST_TIMESTAMP, &ts),
+            (consts::XSLACK_SIGNATURE, &signature),
+        ],
+        body,
+    );
+    assert_eq!(verifier.verify(&test_secrets(), &req).unwrap().id, "k1");
+    let meta = verifier.event_meta(&req).unwrap();
+    assert_eq!(
+        (meta.source.as_str(), meta.topic.as_str()),
+        ("T1", "app_mention")
+    );
+    assert_eq!(meta.triggered_at.timestamp(), 1714990000);
+    assert_eq!(verifier.challenge(&req), None);
+
+    let body = r#"{"token":"t","challenge":"3eZbrw1aBm2rZgRNFdxV2595E9CY3gmdALWMmHkvFXO7tYXAYM8P","type":"url_verification"}"#;
+    let req = test_request(&[], body);
+    assert_eq!(
+        verifier.challenge(&req).as_deref(),
+        Some("3eZbrw1aBm2rZgRNFdxV2595E9CY3gmdALWMmHkvFXO7tYXAYM8P")
+    );
+}
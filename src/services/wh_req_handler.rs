@@ -1,114 +1,522 @@
-use chrono::{DateTime, TimeDelta, Utc};
+use chrono::{TimeDelta, Utc};
 use core::ops::Sub;
 use redis::aio::{ConnectionLike, ConnectionManager};
-use redis::AsyncCommands;
+use std::sync::Arc;
 
-use crate::common::consts;
+use crate::common::{consts, metrics};
+use crate::config;
+use crate::config::{AppConfig, Provider, SchemaFailure, TopicPathCheck};
+use crate::model::error::WebhookError;
 use crate::model::ReqDownstream;
 
+use super::connect::Connected;
 use super::i_wh_req_handler::IWebhookRequestHandleService;
+use super::ingest_rules::{IngestRules, RuleAction};
+use super::load_shed::LoadShedder;
+use super::payload_schema::PayloadSchemas;
+use super::queue_store::{AnyQueueStore, Dedup, Enqueue, QueueStore};
+use super::rate_limit;
+use super::spool::{unreachable, Spool};
+use super::verifier::{self, EventMeta};
 
 #[derive(Clone)]
-pub struct ProductServiceImpl {
-    redis_conn: ConnectionManager,
+pub struct ProductServiceImpl<Store: QueueStore = AnyQueueStore> {
+    // rate limits, unset when they're off (RATE_LIMIT_PER_SEC 0) or until redis answers
+    redis_conn: Connected<ConnectionManager>,
+    store: Connected<Store>,
+    load_shedder: Arc<LoadShedder>,
+    ingest_rules: Arc<IngestRules>,
+    payload_schemas: Arc<PayloadSchemas>,
+    // fallback for the enqueues redis can't take, SPOOL_DIR
+    spool: Option<Arc<Spool>>,
 }
 
-impl ProductServiceImpl {
-    pub fn new(redis_conn: ConnectionManager) -> Self {
-        Self { redis_conn }
+impl<Store: QueueStore> ProductServiceImpl<Store> {
+    pub fn new(
+        redis_conn: Connected<ConnectionManager>,
+        store: Connected<Store>,
+        load_shedder: Arc<LoadShedder>,
+        ingest_rules: Arc<IngestRules>,
+        payload_schemas: Arc<PayloadSchemas>,
+        spool: Option<Arc<Spool>>,
+    ) -> Self {
+        Self {
+            redis_conn,
+            store,
+            load_shedder,
+            ingest_rules,
+            payload_schemas,
+            spool,
+        }
     }
 }
 
-impl std::fmt::Debug for ProductServiceImpl {
+// a verified request, ready to be enqueued unless a rule dropped it
+pub(crate) enum Prepared {
+    Enqueue(Enqueue),
+    Dropped,
+}
+
+// what became of a webhook that was acknowledged
+enum Ingested {
+    Enqueued,
+    // its id was already seen
+    Duplicate,
+    // by an ingest rule
+    Dropped,
+    // an endpoint check, answered with its challenge
+    Challenge(String),
+}
+
+impl<Store: QueueStore> std::fmt::Debug for ProductServiceImpl<Store> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ProductServiceImpl")
-            .field("redis_conn: ", &self.redis_conn.get_db())
+            .field(
+                "redis_conn: ",
+                &self.redis_conn.get().ok().map(|conn| conn.get_db()),
+            )
             .finish()
     }
 }
 
-impl IWebhookRequestHandleService for ProductServiceImpl {
-    async fn handle_webhook_request(&self, request: ReqDownstream) -> anyhow::Result<()> {
-        let mut redis_conn = self.redis_conn.clone();
-        let ser = zstd::bulk::compress(&bitcode::serialize(&request)?, 0)?;
-        // let de_ser = zstd::bulk::decompress(&ser, 100000)?;
+impl<Store: QueueStore> IWebhookRequestHandleService for ProductServiceImpl<Store> {
+    async fn handle_webhook_request(
+        &self,
+        app: &AppConfig,
+        request: ReqDownstream,
+        path_topic: Option<String>,
+    ) -> Result<Option<String>, WebhookError> {
+        // only set once the signature is verified, unauthenticated callers don't get to pick
+        // metric labels
+        let mut verified = None;
+        let res = self
+            .ingest(app, request, path_topic.as_deref(), &mut verified)
+            .await;
+        let topic = verified
+            .as_ref()
+            .map_or(metrics::UNKNOWN, |meta: &EventMeta| meta.topic.as_str());
+        match &res {
+            Ok(Ingested::Enqueued) => {
+                metrics::WEBHOOKS_ACCEPTED
+                    .with_label_values(&[&app.name, topic])
+                    .inc();
+                if config::get().metrics_shop_label
+                    && let Some(meta) = &verified
+                {
+                    metrics::WEBHOOKS_ACCEPTED_BY_SHOP
+                        .with_label_values(&[&app.name, &meta.source, topic])
+                        .inc();
+                }
+            }
+            Ok(Ingested::Duplicate) => metrics::WEBHOOKS_DUPLICATE
+                .with_label_values(&[&app.name, topic])
+                .inc(),
+            Ok(Ingested::Dropped) => metrics::WEBHOOKS_DROPPED
+                .with_label_values(&[&app.name, topic])
+                .inc(),
+            Ok(Ingested::Challenge(_)) => {}
+            Err(e) => metrics::WEBHOOKS_REJECTED
+                .with_label_values(&[&app.name, e.reason(), topic])
+                .inc(),
+        }
+        res.map(|ingested| match ingested {
+            Ingested::Challenge(challenge) => Some(challenge),
+            _ => None,
+        })
+    }
+}
 
-        let shop = request
-            .headers
-            .get(consts::XSHOPIFY_SHOP_DOMAIN)
-            .map_or(
-                Err::<&str, _>(anyhow::anyhow!("no shop domain header")),
-                |h| {
-                    h.to_str().map_err(|e| {
-                        let str = e.to_string().to_owned();
-                        anyhow::anyhow!(str)
-                    })
-                },
-            )?
-            .to_owned();
-
-        let topic = request
-            .headers
-            .get(consts::XSHOPIFY_TOPIC)
-            .map_or(Err::<&str, _>(anyhow::anyhow!("no topic header")), |h| {
-                h.to_str().map_err(|e| {
-                    let str = e.to_string().to_owned();
-                    anyhow::anyhow!(str)
-                })
-            })?
-            .to_owned();
-
-        let triggered_at = request.headers.get(consts::XSHOPIFY_TRIGGERED_AT).map_or(
-            chrono::DateTime::<Utc>::MIN_UTC,
-            |h| {
-                let payload = h.to_str().unwrap_or("");
-                payload
-                    .parse::<DateTime<Utc>>()
-                    .unwrap_or(chrono::DateTime::<Utc>::MIN_UTC)
-            },
-        );
-        // check timestamp, if too old => drop
-        let now = Utc::now();
-        let three_days_ago = now.sub(TimeDelta::try_days(3).unwrap());
-        if triggered_at.lt(&three_days_ago) {
-            return Err(anyhow::anyhow!("event older than 3 days"));
-        };
+impl<Store: QueueStore> ProductServiceImpl<Store> {
+    async fn ingest(
+        &self,
+        app: &AppConfig,
+        mut request: ReqDownstream,
+        path_topic: Option<&str>,
+        verified: &mut Option<EventMeta>,
+    ) -> Result<Ingested, WebhookError> {
+        let cnf = config::get();
+        let path_topic = route_topic(app, &mut request, path_topic);
 
-        let hmac_sig = request.headers.get(consts::XSHOPIFY_HMAC_SHA256).map_or(
-            Err::<&str, _>(anyhow::anyhow!("no hmac header")),
-            |h| {
-                h.to_str().map_err(|e| {
-                    let str = e.to_string().to_owned();
-                    anyhow::anyhow!(str)
-                })
-            },
-        )?;
-
-        let key = config::get().shopify_client_secret.as_bytes();
-        let payload = request.payload.as_bytes();
-
-        match common::crypt::hmac_256_verify(key, payload, hmac_sig) {
-            Ok(_) => {
-                tracing::debug!("hmac verified");
-            }
-            err @ Err(_) => {
-                tracing::debug!("hmac verification failed");
-                return err;
+        let verifier = verifier::for_app(app);
+        let meta = verifier.event_meta(&request)?;
+        check_age(&meta)?;
+
+        // before the hmac so a flood from one shop is turned away cheaply
+        self.load_shedder.check(&meta.topic)?;
+        if let Ok(redis_conn) = self.redis_conn.get()
+            && cnf.rate_limit_per_sec > 0.0
+        {
+            let key = if cnf.rate_limit_per_topic {
+                format!(
+                    "{}:{}:{}",
+                    consts::RATE_LIMIT_KEY_PREFIX,
+                    meta.source,
+                    meta.topic
+                )
+            } else {
+                format!("{}:{}", consts::RATE_LIMIT_KEY_PREFIX, meta.source)
+            };
+            match rate_limit::acquire(
+                &mut redis_conn.clone(),
+                &app.redis_key(&key),
+                cnf.rate_limit_per_sec,
+                cnf.rate_limit_burst,
+            )
+            .await
+            {
+                Ok(None) => {}
+                Ok(Some(wait)) => {
+                    return Err(WebhookError::RateLimited(wait.as_secs_f64().ceil() as u64));
+                }
+                // don't turn webhooks away because the limiter is unavailable, the enqueueing
+                // below will fail anyway if redis is down
+                Err(e) => tracing::warn!("rate limiter unavailable: {e:?}"),
             }
         }
 
+        let secrets = &app.client_secrets;
+        let timer = metrics::HMAC_VERIFY_SECONDS
+            .with_label_values(&[&app.name])
+            .start_timer();
+        let secret = verifier.verify(secrets, &request)?;
+        timer.observe_duration();
+        // anything but the newest secret means the provider still signs with a rotated out one
+        if secrets.first().is_some_and(|newest| newest.id != secret.id) {
+            tracing::info!(
+                "hmac verified with key {} of app {} for {}",
+                secret.id,
+                app.name,
+                meta.source
+            );
+        } else {
+            tracing::debug!("hmac verified with key {}", secret.id);
+        }
+        if let Some(challenge) = verifier.challenge(&request) {
+            tracing::info!("answered the endpoint check of app {}", app.name);
+            return Ok(Ingested::Challenge(challenge));
+        }
+        request.key_id = Some(secret.id.clone());
+        let meta = verified.insert(meta);
+
+        let enqueue = match self.prepare(app, request, path_topic, meta)? {
+            Prepared::Enqueue(enqueue) => enqueue,
+            Prepared::Dropped => return Ok(Ingested::Dropped),
+        };
+        let queue = enqueue.queue.clone();
+
         // to close the conn more quickly when load is high
         // probably need
         // tokio::spawn(async move {
-        redis_conn
-            .zadd(
-                format!("{}:{}", shop, topic),
-                ser,
-                triggered_at.timestamp_millis(),
-            )
-            .await?;
+        let _timer = metrics::REDIS_ENQUEUE_SECONDS
+            .with_label_values(&[&app.name])
+            .start_timer();
+        // only copied when it may have to be spooled
+        let fallback = self.spool.as_ref().map(|spool| (spool, enqueue.clone()));
+        let res = match self.store.get() {
+            Ok(store) => store.enqueue(enqueue).await,
+            Err(e) => Err(e),
+        };
+        let enqueued = match (res, fallback) {
+            (Ok(enqueued), _) => enqueued,
+            (Err(e), Some((spool, enqueue))) if unreachable(&e) => {
+                if let Err(spool_err) = spool.append(&enqueue).await {
+                    tracing::error!("unable to spool a webhook: {spool_err:?}");
+                    return Err(e.into());
+                }
+                tracing::warn!("redis unreachable, spooled webhook for {queue}: {e:?}");
+                metrics::SPOOLED
+                    .with_label_values(&[&app.name, &meta.topic])
+                    .inc();
+                true
+            }
+            (Err(e), _) => return Err(e.into()),
+        };
+        if !enqueued {
+            if let Some(webhook_id) = &meta.event_id {
+                tracing::info!("duplicate webhook {webhook_id} in {queue}, skipped");
+            }
+            return Ok(Ingested::Duplicate);
+        }
+        Ok(Ingested::Enqueued)
         //     Ok::<(), redis::RedisError>(())
         // });
-        Ok(())
     }
+
+    // what's done with a request once its signature is verified, by the receiver and the admin
+    // ingest alike: the topic check, the ingest rules, the payload's schema, then the header
+    // policy and the envelope, with its dedup
+    pub(crate) fn prepare(
+        &self,
+        app: &AppConfig,
+        mut request: ReqDownstream,
+        path_topic: Option<&str>,
+        meta: &EventMeta,
+    ) -> Result<Prepared, WebhookError> {
+        let cnf = config::get();
+        if let Some(path_topic) = path_topic
+            && path_topic != meta.topic
+        {
+            // the route isn't signed, only logged so it can't grow the metric's series
+            metrics::TOPIC_MISMATCHES
+                .with_label_values(&[&app.name, &meta.topic])
+                .inc();
+            tracing::warn!(
+                "{} webhook of {} sent to the route of {path_topic:?}",
+                meta.topic,
+                meta.source
+            );
+            if cnf.topic_path_check == TopicPathCheck::Strict {
+                return Err(WebhookError::TopicMismatch {
+                    topic: meta.topic.clone(),
+                    path_topic: path_topic.to_string(),
+                });
+            }
+        }
+
+        let api_version = request
+            .headers
+            .get(consts::XSHOPIFY_APIVERSION)
+            .and_then(|v| v.to_str().ok());
+        match self
+            .ingest_rules
+            .current()
+            .action(&app.name, &meta.topic, &meta.source, api_version)
+        {
+            RuleAction::Accept => {}
+            RuleAction::Drop => {
+                tracing::debug!("dropped {} webhook of {}", meta.topic, meta.source);
+                return Ok(Prepared::Dropped);
+            }
+            RuleAction::Reject => return Err(WebhookError::Refused),
+        }
+
+        let mut queue = app.queue_key(&meta.source, &meta.topic);
+        let mut schema_errors = None;
+        if let Some((on_failure, errors)) =
+            self.payload_schemas.validate(&meta.topic, &request.payload)
+        {
+            for error in &errors {
+                metrics::SCHEMA_FAILURES
+                    .with_label_values(&[&app.name, &meta.topic, &error.schema_path])
+                    .inc();
+            }
+            let summary = errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ");
+            match on_failure {
+                SchemaFailure::Reject => return Err(WebhookError::InvalidPayload(summary)),
+                SchemaFailure::Quarantine => {
+                    tracing::warn!(
+                        "quarantined {} webhook of {}: {summary}",
+                        meta.topic,
+                        meta.source
+                    );
+                    queue = app.quarantine_key(&meta.source, &meta.topic);
+                }
+                SchemaFailure::Flag => schema_errors = Some(errors),
+            }
+        }
+
+        // only once the verifier is done with the headers
+        cnf.ingest_header_policy
+            .apply(app.provider, &mut request.headers);
+        // the failing schema paths, messages may quote the payload
+        if let Some(errors) = schema_errors {
+            let paths = errors
+                .iter()
+                .map(|e| e.schema_path.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if let Ok(value) = http::HeaderValue::try_from(paths) {
+                request
+                    .headers
+                    .insert(consts::XWEBHOOK_SCHEMA_ERRORS, value);
+            }
+        }
+
+        let timer = metrics::ENVELOPE_ENCODE_SECONDS
+            .with_label_values(&[&app.name])
+            .start_timer();
+        let ser = request.to_envelope().map_err(WebhookError::Encoding)?;
+        timer.observe_duration();
+        metrics::ENVELOPE_BYTES
+            .with_label_values(&[&app.name])
+            .observe(ser.len() as f64);
+        // let de_ser = zstd::bulk::decompress(&ser, 100000)?;
+
+        let dedup_ttl = cnf.webhook_dedup_ttl;
+        let dedup = match &meta.event_id {
+            Some(webhook_id) if dedup_ttl > 0 => Some(Dedup {
+                id_key: app.redis_key(&format!("{}:{}", consts::WEBHOOK_ID_KEY_PREFIX, webhook_id)),
+                ttl: dedup_ttl,
+                stats_key: app.redis_key(consts::DUPLICATE_WEBHOOKS_KEY),
+                topic: meta.topic.clone(),
+            }),
+            _ => None,
+        };
+        Ok(Prepared::Enqueue(Enqueue {
+            queue,
+            score: meta.triggered_at.timestamp_millis(),
+            envelope: ser,
+            dedup,
+        }))
+    }
+}
+
+// the route's {resource}/{topic} when it's checked against the request's topic: only shopify's
+// routes name one. It stands in for a missing x-shopify-topic
+pub(crate) fn route_topic<'a>(
+    app: &AppConfig,
+    request: &mut ReqDownstream,
+    path_topic: Option<&'a str>,
+) -> Option<&'a str> {
+    let path_topic = path_topic.filter(|_| {
+        app.provider == Provider::Shopify && config::get().topic_path_check != TopicPathCheck::Off
+    });
+    if let Some(path_topic) = path_topic
+        && !request.headers.contains_key(consts::XSHOPIFY_TOPIC)
+        && let Ok(value) = http::HeaderValue::try_from(path_topic)
+    {
+        request.headers.insert(consts::XSHOPIFY_TOPIC, value);
+    }
+    path_topic
+}
+
+// check timestamp, if too old => drop
+pub(crate) fn check_age(meta: &EventMeta) -> Result<(), WebhookError> {
+    let three_days_ago = Utc::now().sub(TimeDelta::try_days(3).unwrap());
+    if meta.triggered_at.lt(&three_days_ago) {
+        return Err(WebhookError::ExpiredEvent(meta.triggered_at));
+    };
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handle_webhook_request() {
+    use super::queue_store::MemoryQueueStore;
+    use data_encoding::BASE64;
+
+    config::init_test_env();
+    let app = config::get().app(config::DEFAULT_APP).unwrap();
+    let store = MemoryQueueStore::new();
+    let svc = ProductServiceImpl::new(
+        Connected::pending(),
+        Connected::ready(store.clone()),
+        Arc::new(LoadShedder::new()),
+        Arc::new(IngestRules::default()),
+        Arc::new(PayloadSchemas::default()),
+        None,
+    );
+    let payload = br#"{"id":9079211262258}"#;
+    let key = ring::hmac::Key::new(
+        ring::hmac::HMAC_SHA256,
+        app.client_secrets[0].secret.as_bytes(),
+    );
+    let request = |webhook_id: &str| {
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in [
+            (consts::XSHOPIFY_SHOP_DOMAIN, "a.myshopify.com".to_string()),
+            (consts::XSHOPIFY_TOPIC, "orders/create".to_string()),
+            (consts::XSHOPIFY_TRIGGERED_AT, Utc::now().to_rfc3339()),
+            (consts::XSHOPIFY_WEBHOOK_ID, webhook_id.to_string()),
+            (
+                consts::XSHOPIFY_HMAC_SHA256,
+                BASE64.encode(ring::hmac::sign(&key, payload).as_ref()),
+            ),
+        ] {
+            headers.insert(name, value.parse().unwrap());
+        }
+        ReqDownstream {
+            endpoint: "/webhooks".to_string(),
+            method: http::Method::POST,
+            headers,
+            queries: Default::default(),
+            payload: bytes::Bytes::from_static(payload),
+            key_id: None,
+        }
+    };
+
+    let queue = app.queue_key("a.myshopify.com", "orders/create");
+    svc.handle_webhook_request(app, request("1"), None)
+        .await
+        .unwrap();
+    let claimed = store.claim(&queue, 0, 10).await.unwrap();
+    assert_eq!(claimed.len(), 1);
+    let queued = ReqDownstream::from_envelope(&claimed[0].request).unwrap();
+    assert_eq!(queued.payload.as_ref(), payload);
+    assert_eq!(queued.key_id.as_deref(), Some("default"));
+
+    // redelivered by shopify, acknowledged but not queued again
+    svc.handle_webhook_request(app, request("1"), None)
+        .await
+        .unwrap();
+    assert_eq!(store.stats(&queue).await.unwrap().depth, 1);
+    let stats_key = app.redis_key(consts::DUPLICATE_WEBHOOKS_KEY);
+    assert_eq!(store.duplicates(&stats_key, "orders/create"), 1);
+
+    let mut forged = request("2");
+    forged
+        .headers
+        .insert(consts::XSHOPIFY_HMAC_SHA256, "bm9wZQ==".parse().unwrap());
+    assert!(matches!(
+        svc.handle_webhook_request(app, forged, None).await,
+        Err(WebhookError::BadSignature)
+    ));
+    assert_eq!(store.stats(&queue).await.unwrap().depth, 1);
+}
+
+#[tokio::test]
+async fn test_slack_url_verification() {
+    use super::queue_store::MemoryQueueStore;
+    use super::verifier::{sign_hex, test_request, test_secrets};
+
+    config::init_test_env();
+    let app = AppConfig {
+        name: "slack".to_string(),
+        provider: Provider::Slack,
+        client_secrets: test_secrets(),
+        signature_tolerance: 300,
+        queue_prefix: "slack".to_string(),
+        downstream_app_url: String::new(),
+        kafka_topic: String::new(),
+    };
+    let store = MemoryQueueStore::new();
+    let svc = ProductServiceImpl::new(
+        Connected::pending(),
+        Connected::ready(store.clone()),
+        Arc::new(LoadShedder::new()),
+        Arc::new(IngestRules::default()),
+        Arc::new(PayloadSchemas::default()),
+        None,
+    );
+    let body = r#"{"token":"t","challenge":"c4ll3ng3","type":"url_verification"}"#;
+    let ts = Utc::now().timestamp().to_string();
+    let signature = format!(
+        "v0={}",
+        sign_hex("s3cr3t", format!("v0:{ts}:{body}").as_bytes())
+    );
+    let request = |signature: &str| {
+        test_request(
+            &[
+                (consts::XSLACK_REQUEST_TIMESTAMP, &ts),
+                (consts::XSLACK_SIGNATURE, signature),
+            ],
+            body,
+        )
+    };
+
+    let reply = svc
+        .handle_webhook_request(&app, request(&signature), None)
+        .await
+        .unwrap();
+    assert_eq!(reply.as_deref(), Some("c4ll3ng3"));
+    assert!(store.queues().await.unwrap().is_empty());
+    // only signed checks are answered
+    assert!(matches!(
+        svc.handle_webhook_request(&app, request("v0=00"), None)
+            .await,
+        Err(WebhookError::BadSignature)
+    ));
 }
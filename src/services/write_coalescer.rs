@@ -0,0 +1,278 @@
+use redis::aio::ConnectionLike;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+use super::queue_store::redis_store::ENQUEUE_ONCE_SCRIPT;
+use super::queue_store::Enqueue;
+use crate::common::metrics;
+
+type Pending = (Enqueue, oneshot::Sender<redis::RedisResult<bool>>);
+
+// gathers the receiver's writes for up to `linger` (or `max_batch` writes) and sends them as one
+// pipeline, or MULTI/EXEC when atomic. Callers still wait for their own write to be confirmed
+#[derive(Debug, Clone)]
+pub struct WriteCoalescer {
+    tx: mpsc::Sender<Pending>,
+}
+
+impl WriteCoalescer {
+    // must be called from within the tokio runtime
+    pub fn start<C: ConnectionLike + Clone + Send + 'static>(
+        redis_conn: C,
+        max_batch: usize,
+        linger: Duration,
+        atomic: bool,
+    ) -> Self {
+        let max_batch = max_batch.max(1);
+        metrics::WRITE_BATCH_MAX_SIZE.set(max_batch as i64);
+        metrics::WRITE_BATCH_LINGER_SECONDS.set(linger.as_secs_f64());
+        // enough for a few batches in flight, then webhooks wait for room
+        let (tx, rx) = mpsc::channel(max_batch * 16);
+        tokio::spawn(gather(rx, redis_conn, max_batch, linger, atomic));
+        Self { tx }
+    }
+
+    pub async fn enqueue(&self, enqueue: Enqueue) -> redis::RedisResult<bool> {
+        let closed =
+            || redis::RedisError::from((redis::ErrorKind::IoError, "write coalescer stopped"));
+        let (reply, confirmed) = oneshot::channel();
+        self.tx.send((enqueue, reply)).await.map_err(|_| closed())?;
+        confirmed.await.map_err(|_| closed())?
+    }
+}
+
+async fn gather<C: ConnectionLike + Clone + Send + 'static>(
+    mut rx: mpsc::Receiver<Pending>,
+    mut redis_conn: C,
+    max_batch: usize,
+    linger: Duration,
+    atomic: bool,
+) {
+    if let Err(e) = ENQUEUE_ONCE_SCRIPT
+        .prepare_invoke()
+        .load_async(&mut redis_conn)
+        .await
+    {
+        tracing::warn!("unable to load the enqueue script, retrying with the first batch: {e:?}");
+    }
+    while let Some(first) = rx.recv().await {
+        let started = Instant::now();
+        let deadline = tokio::time::Instant::from_std(started + linger);
+        let mut batch = Vec::with_capacity(max_batch);
+        batch.push(first);
+        while batch.len() < max_batch {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(pending)) => batch.push(pending),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        metrics::WRITE_BATCH_SIZE.observe(batch.len() as f64);
+        metrics::WRITE_BATCH_WAIT_SECONDS.observe(started.elapsed().as_secs_f64());
+        // batches are independent, the queues order events by score anyway
+        tokio::spawn(flush(redis_conn.clone(), batch, atomic));
+    }
+}
+
+async fn flush<C: ConnectionLike>(mut redis_conn: C, batch: Vec<Pending>, atomic: bool) {
+    let mut pipe = redis::pipe();
+    if atomic {
+        pipe.atomic();
+    }
+    for (enqueue, _) in &batch {
+        enqueue.add_to(&mut pipe);
+    }
+    let mut res: redis::RedisResult<Vec<redis::Value>> = pipe.query_async(&mut redis_conn).await;
+    // the script was flushed (or never loaded), writes that went through are idempotent, and
+    // deduplicated ones count as duplicates on the second try
+    if res
+        .as_ref()
+        .is_err_and(|e| e.kind() == redis::ErrorKind::NoScriptError)
+    {
+        res = match ENQUEUE_ONCE_SCRIPT
+            .prepare_invoke()
+            .load_async(&mut redis_conn)
+            .await
+        {
+            Ok(_) => pipe.query_async(&mut redis_conn).await,
+            Err(e) => Err(e),
+        };
+    }
+    match res {
+        Ok(replies) => {
+            for ((enqueue, reply), value) in batch.into_iter().zip(replies) {
+                let _ = reply.send(Ok(enqueue.enqueued(&value)));
+            }
+        }
+        Err(e) => {
+            tracing::error!("failed to write a batch of {} webhooks: {e:?}", batch.len());
+            // RedisError isn't Clone
+            for (_, reply) in batch {
+                let err =
+                    redis::RedisError::from((e.kind(), "batched write failed", e.to_string()));
+                let _ = reply.send(Err(err));
+            }
+        }
+    }
+}
+
+// answers pipelines of ZADDs and enqueue-once EVALSHAs like redis would, and records them
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct FakeRedis {
+    state: std::sync::Arc<std::sync::Mutex<FakeState>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct FakeState {
+    script_loaded: bool,
+    script_loads: usize,
+    seen_ids: std::collections::HashSet<Vec<u8>>,
+    // size of each pipeline received, and whether it was MULTI/EXEC
+    pipelines: Vec<(usize, bool)>,
+    down: bool,
+}
+
+#[cfg(test)]
+impl ConnectionLike for FakeRedis {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        let mut state = self.state.lock().unwrap();
+        let is_load = matches!(cmd.args_iter().next(), Some(redis::Arg::Simple(b"SCRIPT")));
+        let res = if state.down {
+            Err((redis::ErrorKind::IoError, "connection refused").into())
+        } else if is_load {
+            state.script_loaded = true;
+            state.script_loads += 1;
+            Ok(redis::Value::Data(
+                ENQUEUE_ONCE_SCRIPT.get_hash().as_bytes().to_vec(),
+            ))
+        } else {
+            Ok(redis::Value::Okay)
+        };
+        Box::pin(async move { res })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipe: &'a redis::Pipeline,
+        offset: usize,
+        _count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        let mut state = self.state.lock().unwrap();
+        let atomic = offset > 0;
+        state.pipelines.push((pipe.cmd_iter().count(), atomic));
+        let res = if state.down {
+            Err((redis::ErrorKind::IoError, "connection refused").into())
+        } else {
+            let args = |cmd: &'a redis::Cmd| -> Vec<&'a [u8]> {
+                cmd.args_iter()
+                    .filter_map(|arg| match arg {
+                        redis::Arg::Simple(arg) => Some(arg),
+                        redis::Arg::Cursor => None,
+                    })
+                    .collect()
+            };
+            let mut replies = vec![];
+            let mut res = Ok(());
+            for cmd in pipe.cmd_iter() {
+                let args = args(cmd);
+                if args[0] == b"EVALSHA" {
+                    if !state.script_loaded {
+                        res = Err((redis::ErrorKind::NoScriptError, "NOSCRIPT").into());
+                        break;
+                    }
+                    let fresh = state.seen_ids.insert(args[3].to_vec());
+                    replies.push(redis::Value::Int(fresh as i64));
+                } else {
+                    replies.push(redis::Value::Int(1));
+                }
+            }
+            res.map(|()| {
+                if atomic {
+                    vec![redis::Value::Bulk(replies)]
+                } else {
+                    replies
+                }
+            })
+        };
+        Box::pin(async move { res })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[cfg(test)]
+fn test_enqueue(envelope: &str, id: Option<&str>) -> Enqueue {
+    Enqueue {
+        queue: "a.myshopify.com:orders/create".to_string(),
+        score: 1717400000000,
+        envelope: envelope.as_bytes().to_vec(),
+        dedup: id.map(|id| super::queue_store::Dedup {
+            id_key: format!("webhook-id:{id}"),
+            ttl: 60,
+            stats_key: "stats:duplicate-webhooks".to_string(),
+            topic: "orders/create".to_string(),
+        }),
+    }
+}
+
+#[tokio::test]
+async fn test_write_coalescer_batches() {
+    for atomic in [false, true] {
+        let redis = FakeRedis::default();
+        let coalescer = WriteCoalescer::start(redis.clone(), 3, Duration::from_millis(50), atomic);
+        // the first three fill a batch, the fourth waits out the linger on its own
+        let results = futures::future::join_all([
+            coalescer.enqueue(test_enqueue("a", Some("1"))),
+            coalescer.enqueue(test_enqueue("b", Some("1"))),
+            coalescer.enqueue(test_enqueue("c", None)),
+            coalescer.enqueue(test_enqueue("d", Some("2"))),
+        ])
+        .await;
+        let results: Vec<bool> = results.into_iter().map(Result::unwrap).collect();
+        // each caller gets its own write's reply, the second is a duplicate
+        assert_eq!(results, vec![true, false, true, true]);
+        let state = redis.state.lock().unwrap();
+        assert_eq!(state.pipelines, vec![(3, atomic), (1, atomic)]);
+    }
+}
+
+#[tokio::test]
+async fn test_write_coalescer_failures() {
+    let redis = FakeRedis::default();
+    let coalescer = WriteCoalescer::start(redis.clone(), 10, Duration::from_millis(10), false);
+    assert!(coalescer
+        .enqueue(test_enqueue("a", Some("1")))
+        .await
+        .unwrap());
+    // SCRIPT FLUSH: the batch is retried once the script is loaded again
+    redis.state.lock().unwrap().script_loaded = false;
+    assert!(coalescer
+        .enqueue(test_enqueue("b", Some("2")))
+        .await
+        .unwrap());
+    {
+        let state = redis.state.lock().unwrap();
+        assert_eq!(state.script_loads, 2);
+        assert_eq!(state.pipelines.len(), 3);
+    }
+    // a failed batch fails every write in it
+    redis.state.lock().unwrap().down = true;
+    let results = futures::future::join_all([
+        coalescer.enqueue(test_enqueue("c", Some("3"))),
+        coalescer.enqueue(test_enqueue("d", None)),
+    ])
+    .await;
+    for res in results {
+        assert_eq!(res.unwrap_err().kind(), redis::ErrorKind::IoError);
+    }
+    assert_eq!(
+        redis.state.lock().unwrap().pipelines.last(),
+        Some(&(2, false))
+    );
+}